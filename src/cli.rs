@@ -1,23 +1,733 @@
+use payments_engine::engine::audit::{verify_audit_trail, FileAuditSubscriber};
+use payments_engine::engine::checkpoint::CheckpointConfig;
+use payments_engine::engine::config::Config;
+use payments_engine::engine::diff::{read_accounts, AccountDiff};
+use payments_engine::engine::download::downloadable_for_uri;
+use payments_engine::engine::dry_run::DryRunReport;
 use payments_engine::engine::errors::PaymentError;
 use payments_engine::engine::ingestion::IngestionService;
+use payments_engine::engine::journal::{replay, verify, Journal};
+use payments_engine::engine::payments::AccountService;
+use payments_engine::engine::policy::Policy;
+use payments_engine::engine::reconcile::{read_expected_balances, ReconciliationReport};
+use payments_engine::engine::report::{OutputFormat, ReportOptions};
+use payments_engine::engine::retention::{Retention, RetentionPolicy};
+use payments_engine::engine::snapshot::{load_snapshot, save_snapshot};
+use payments_engine::engine::statement::Statement;
+use payments_engine::engine::store::FileStore;
+use payments_engine::engine::upload::uploadable_for_uri;
+use payments_engine::EngineBuilder;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
 
-pub struct CLI {
-    ingestion_service: IngestionService,
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000;
+
+const USAGE: &str = "\
+Usage: payments_engine [command] <args> [flags]
+
+Commands:
+  process [input]              apply the transactions in <input>, or the config sources, and
+                               output the accounts (default)
+  validate <input>             dry run of <input> on top of the current state without changing
+                               it, output whether every row would be applied
+  replay <journal>             rebuild the accounts from a journal and output them
+  reconcile <input> <expected> process <input> and output the differences with the expected
+                               balances, fails if there are any
+  inspect <client>             output the statement of <client> built from --journal, on top of
+                               --load-state if the journal was started from it
+  diff <before> <after>        output the differences between two account reports or snapshots
+  help                         print this message
+
+Inputs are paths or file:// and s3:// uris.
+
+Flags:
+  --config <path>              read settings from the toml file at <path>, flags take precedence
+  --workers <n>                number of workers processing transactions, 1 by default. The rows
+                               are split between them by client id
+  --checkpoint <path>          periodically record progress to <path>
+  --checkpoint-interval <n>    number of lines between checkpoints
+  --resume                     continue from the checkpoint instead of starting over
+  --load-state <path>          start from a previously saved snapshot of the accounts
+  --save-state <path>          save a snapshot of the accounts once processing is done
+  --journal <path>             append every transaction to the journal at <path>
+  --verify <path>              check the replayed accounts match the snapshot at <path>
+  --store <dir>                keep the accounts in files under <dir> instead of in memory
+  --max-history <n>            keep at most <n> transactions per account for disputes
+  --max-history-age <secs>     evict transactions kept for disputes after <secs>
+  --dispute-window <secs>      transactions can no longer be disputed after <secs>
+  --spill <dir>                write evicted transactions to <dir> so they can still be disputed
+  --sort <client|balance>      order of the accounts in the output, by client id by default
+  --only-locked                only output locked accounts
+  --only-non-zero              only output accounts with a non-zero balance
+  --format <format>            csv (default), json, jsonl or table
+  --output <uri>               write the output to a path or uri instead of stdout
+  --projected <uri>            write the accounts as they would be after validate to a path or uri
+  --trial-balance <uri>        write the ledger trial balance to a path or uri
+  --audit <path>               append a record of every balance change to the audit trail at <path>
+  --verify-audit <path>        check the audit trail at <path> is consistent with the accounts
+  --stats                      print a summary of the run to stderr
+  --stats-json <uri>           write a summary of the run as json to a path or uri
+  --help                       print this message
+
+Exit codes:
+  0  success
+  1  a check failed: reconciliation, verification, trial balance or invalid rows in a dry run
+  2  invalid arguments or config
+  3  an input or state file couldn't be parsed
+  4  processing would have broken an invariant of the engine
+  5  local state couldn't be read or written
+  6  an input or config file couldn't be read or downloaded, or an output uploaded
+  7  a worker failed
+";
+
+// Each kind of error has its own exit code so scripts can tell failures apart, see `USAGE`
+pub fn exit_code(error: &PaymentError) -> i32 {
+    match error {
+        PaymentError::VerificationError(_) => 1,
+        PaymentError::CliError(_) | PaymentError::ConfigError(_) => 2,
+        PaymentError::ParseError(_) => 3,
+        PaymentError::PolicyError(_) => 4,
+        PaymentError::StorageError { .. } => 5,
+        PaymentError::SourceError { .. } => 6,
+        PaymentError::WorkerError(_) => 7,
+    }
+}
+
+// Named before clippy ran on the project
+#[allow(clippy::upper_case_acronyms)]
+pub struct CLI {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Command {
+    #[default]
+    Process,
+    Validate,
+    Replay,
+    Reconcile,
+    Inspect,
+    Diff,
+    Help,
+}
+
+impl Command {
+    fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "process" => Some(Command::Process),
+            "validate" => Some(Command::Validate),
+            "replay" => Some(Command::Replay),
+            "reconcile" => Some(Command::Reconcile),
+            "inspect" => Some(Command::Inspect),
+            "diff" => Some(Command::Diff),
+            "help" => Some(Command::Help),
+            _ => None,
+        }
+    }
+
+    // Number of positional args the command takes
+    fn arity(&self) -> usize {
+        match self {
+            Command::Help => 0,
+            Command::Process | Command::Validate | Command::Replay | Command::Inspect => 1,
+            Command::Reconcile | Command::Diff => 2,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    command: Command,
+    args: Vec<String>,
+    sources: Vec<String>,
+    policy: Policy,
+    workers: Option<u8>,
+    checkpoint: Option<String>,
+    checkpoint_interval: Option<u64>,
+    resume: bool,
+    load_state: Option<String>,
+    save_state: Option<String>,
+    journal: Option<String>,
+    verify: Option<String>,
+    store: Option<String>,
+    retention: RetentionPolicy,
+    spill: Option<String>,
+    report: ReportOptions,
+    format: OutputFormat,
+    output: Option<String>,
+    projected: Option<String>,
+    trial_balance: Option<String>,
+    stats: bool,
+    stats_json: Option<String>,
+    audit: Option<String>,
+    verify_audit: Option<String>,
+}
+
+impl Options {
+    // Discard first arg which is the cwd. The command is optional so that
+    // `payments_engine <input>` keeps working as `process`, see `USAGE` for the flags.
+    fn parse(args: &[String]) -> Result<Self, PaymentError> {
+        let config = match config_path(args)? {
+            Some(path) => Config::load(Path::new(&path))?,
+            None => Config::default(),
+        };
+        let mut options = Options {
+            workers: config.workers,
+            sources: config.sources,
+            policy: config.policy,
+            report: config.report,
+            ..Default::default()
+        };
+        let mut format = None;
+        let mut output = None;
+        let mut args = args.iter().skip(1).peekable();
+        if let Some(command) = args.peek().and_then(|arg| Command::from_arg(arg)) {
+            options.command = command;
+            args.next();
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => options.command = Command::Help,
+                // Already applied before any flag so the flags take precedence
+                "--config" => {
+                    args.next();
+                }
+                "--workers" => options.workers = Some(parse_flag_value(arg, args.next())?),
+                "--checkpoint" => options.checkpoint = Some(flag_value(arg, args.next())?),
+                "--checkpoint-interval" => {
+                    options.checkpoint_interval = Some(parse_flag_value(arg, args.next())?)
+                }
+                "--resume" => options.resume = true,
+                "--load-state" => options.load_state = Some(flag_value(arg, args.next())?),
+                "--save-state" => options.save_state = Some(flag_value(arg, args.next())?),
+                "--journal" => options.journal = Some(flag_value(arg, args.next())?),
+                "--verify" => options.verify = Some(flag_value(arg, args.next())?),
+                "--store" => options.store = Some(flag_value(arg, args.next())?),
+                "--max-history" => {
+                    options.retention.max_count = Some(parse_flag_value(arg, args.next())?)
+                }
+                "--max-history-age" => {
+                    options.retention.max_age =
+                        Some(Duration::from_secs(parse_flag_value(arg, args.next())?))
+                }
+                "--dispute-window" => {
+                    options.retention.dispute_window =
+                        Some(Duration::from_secs(parse_flag_value(arg, args.next())?))
+                }
+                "--spill" => options.spill = Some(flag_value(arg, args.next())?),
+                "--sort" => options.report.sort = parse_flag_value(arg, args.next())?,
+                "--only-locked" => options.report.only_locked = true,
+                "--only-non-zero" => options.report.only_non_zero = true,
+                "--format" => format = Some(parse_flag_value(arg, args.next())?),
+                "--output" => output = Some(flag_value(arg, args.next())?),
+                "--projected" => options.projected = Some(flag_value(arg, args.next())?),
+                "--trial-balance" => options.trial_balance = Some(flag_value(arg, args.next())?),
+                "--audit" => options.audit = Some(flag_value(arg, args.next())?),
+                "--verify-audit" => options.verify_audit = Some(flag_value(arg, args.next())?),
+                "--stats" => options.stats = true,
+                "--stats-json" => options.stats_json = Some(flag_value(arg, args.next())?),
+                _ if arg.starts_with("--") => {
+                    return Err(PaymentError::CliError(format!("Unknown flag: {}", arg)))
+                }
+                _ => options.args.push(arg.clone()),
+            }
+        }
+
+        if options.command == Command::Help {
+            return Ok(options);
+        }
+        // The output settings of the config are for the accounts report, the other reports
+        // are only changed by the flags
+        let (config_format, config_output) = match options.command {
+            Command::Process | Command::Replay => (config.format, config.output),
+            Command::Validate => (config.format, None),
+            _ => (None, None),
+        };
+        options.format = format.or(config_format).unwrap_or_default();
+        options.output = output.or(config_output);
+        let sources_from_config = options.command == Command::Process
+            && options.args.is_empty()
+            && !options.sources.is_empty();
+        if options.args.len() != options.command.arity() && !sources_from_config {
+            return Err(PaymentError::CliError(format!(
+                "Expected {} argument(s), got {}. Run with --help for usage",
+                options.command.arity(),
+                options.args.len()
+            )));
+        }
+        if options.workers == Some(0) {
+            return Err(PaymentError::CliError(
+                "--workers must be at least 1".to_string(),
+            ));
+        }
+        match options.command {
+            Command::Inspect => {
+                if options.journal.is_none() {
+                    return Err(PaymentError::CliError(
+                        "inspect requires --journal".to_string(),
+                    ));
+                }
+                if options.format != OutputFormat::Csv && options.format != OutputFormat::Json {
+                    return Err(PaymentError::CliError(
+                        "Statements can only be written as csv or json".to_string(),
+                    ));
+                }
+            }
+            Command::Reconcile | Command::Diff if options.format != OutputFormat::Csv => {
+                return Err(PaymentError::CliError(
+                    "This command can only write csv".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if options.checkpoint.is_some() && sources_from_config && options.sources.len() > 1 {
+            return Err(PaymentError::CliError(
+                "--checkpoint only supports a single input".to_string(),
+            ));
+        }
+        if options.resume && options.checkpoint.is_none() {
+            return Err(PaymentError::CliError(
+                "--resume requires --checkpoint".to_string(),
+            ));
+        }
+        if options.verify.is_some() && options.command != Command::Replay {
+            return Err(PaymentError::CliError(
+                "--verify can only be used with replay".to_string(),
+            ));
+        }
+        Ok(options)
+    }
 }
 
 impl CLI {
-    pub fn new(ingestion_service: IngestionService) -> Self {
-        Self { ingestion_service }
+    pub fn new() -> Self {
+        Self {}
     }
 
     pub async fn execute(&self, args: Vec<String>) -> Result<(), PaymentError> {
-        // Discard first arg which is the cwd
-        // Assume only 1 positional arg which is a file path
-        if args.len() < 2 {
-            return Err(PaymentError::CliError("Provide input file".to_string()));
-        }
-        self.ingestion_service
-            .submit_payments_csv(&format!("file://{}", args[1]))
-            .await
+        let options = Options::parse(&args)?;
+        let mut retention = Retention::new(options.retention.clone());
+        if let Some(spill) = &options.spill {
+            retention = retention.with_spill(Path::new(spill))?;
+        }
+
+        match options.command {
+            Command::Help => write_stdout(USAGE.as_bytes()),
+            Command::Diff => {
+                let diff = AccountDiff::new(
+                    &read_accounts(Path::new(&options.args[0]))?,
+                    &read_accounts(Path::new(&options.args[1]))?,
+                );
+                let mut output = Vec::new();
+                diff.write_csv(&mut output)?;
+                write_output(&options, &output).await
+            }
+            Command::Inspect => {
+                let client_id = options.args[0]
+                    .parse()
+                    .map_err(|_| PaymentError::CliError("Invalid client id".to_string()))?;
+                let journal = options.journal.as_deref().unwrap_or_default();
+                // Built with the policy and retention the journal was written with so
+                // disputes come out the same as they did in the run
+                let statement = Statement::from_journal_with(
+                    Path::new(journal),
+                    client_id,
+                    options.policy,
+                    retention.read_only(),
+                    options.load_state.as_deref().map(Path::new),
+                )?;
+                let mut output = Vec::new();
+                match options.format {
+                    OutputFormat::Json => statement.write_json(&mut output)?,
+                    _ => statement.write_csv(&mut output)?,
+                }
+                write_output(&options, &output).await
+            }
+            Command::Validate => self.validate(&options, retention).await,
+            Command::Process | Command::Replay | Command::Reconcile => {
+                self.run(&options, retention).await
+            }
+        }
+    }
+
+    // Dry run of the input on top of the current state, the report says what would happen
+    // to every row. Nothing is written to the state, journal, audit trail or spill.
+    async fn validate(&self, options: &Options, retention: Retention) -> Result<(), PaymentError> {
+        // --load-state replaces whatever is in the store, so the store is only read without it
+        let account_service = match (&options.store, &options.load_state) {
+            (Some(dir), None) => {
+                AccountService::with_store(Box::new(FileStore::open(Path::new(dir))?))
+            }
+            _ => AccountService::new(),
+        };
+        account_service.set_retention(retention);
+        account_service.set_policy(options.policy);
+        if let Some(load_state) = &options.load_state {
+            load_snapshot(&account_service, Path::new(load_state))?;
+        }
+        let lines = downloadable_for_uri(&to_uri(&options.args[0]))?
+            .download()
+            .await?;
+        let dry_run = DryRunReport::new(&account_service, lines)?;
+
+        let mut report = Vec::new();
+        dry_run.write_csv(&mut report)?;
+        write_output(options, &report).await?;
+        if let Some(projected_uri) = &options.projected {
+            let mut projected = Vec::new();
+            options.format.writer().write_accounts(
+                &options.report.apply(dry_run.projected.clone()),
+                &mut projected,
+            )?;
+            uploadable_for_uri(&to_uri(projected_uri))?
+                .upload(&projected)
+                .await?;
+        }
+
+        if dry_run.invalid_rows() > 0 {
+            return Err(PaymentError::VerificationError(format!(
+                "{} invalid rows",
+                dry_run.invalid_rows()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn run(&self, options: &Options, retention: Retention) -> Result<(), PaymentError> {
+        let mut builder = EngineBuilder::new()
+            .with_workers(options.workers.unwrap_or(1))
+            .with_policy(options.policy)
+            .with_retention(retention);
+        if let Some(dir) = &options.store {
+            builder = builder.with_store(Box::new(FileStore::open(Path::new(dir))?));
+        }
+        let (ingestion_service, account_service) = builder.build()?.into_parts();
+
+        if let Some(load_state) = &options.load_state {
+            load_snapshot(&account_service, Path::new(load_state))?;
+        }
+
+        if options.command == Command::Replay {
+            replay(Path::new(&options.args[0]), &account_service)?;
+            if let Some(snapshot) = &options.verify {
+                verify(&account_service, Path::new(snapshot))?;
+            }
+        } else {
+            if let Some(journal) = &options.journal {
+                account_service.set_journal(Journal::open(Path::new(journal))?);
+            }
+            if let Some(audit) = &options.audit {
+                account_service
+                    .add_observer(Box::new(FileAuditSubscriber::open(Path::new(audit))?));
+            }
+            // An input given on the command line replaces the config sources
+            let inputs = match options.args.first() {
+                Some(input) => vec![input.clone()],
+                None => options.sources.clone(),
+            };
+            for input in inputs {
+                self.process(options, ingestion_service.clone(), &to_uri(&input))
+                    .await?;
+            }
+        }
+
+        if let Some(save_state) = &options.save_state {
+            save_snapshot(&account_service, Path::new(save_state))?;
+        }
+        if let Some(audit) = &options.verify_audit {
+            verify_audit_trail(Path::new(audit), &account_service)?;
+        }
+        write_stats(options, &account_service).await?;
+        if let Some(trial_balance_uri) = &options.trial_balance {
+            let trial_balance = account_service.trial_balance()?;
+            let mut contents = Vec::new();
+            trial_balance.write_csv(&mut contents)?;
+            uploadable_for_uri(&to_uri(trial_balance_uri))?
+                .upload(&contents)
+                .await?;
+            if trial_balance.total != 0.0 {
+                return Err(PaymentError::VerificationError(format!(
+                    "Trial balance does not net to zero: {}",
+                    trial_balance.total
+                )));
+            }
+            if !trial_balance.is_balanced() {
+                return Err(PaymentError::VerificationError(format!(
+                    "{} ledger accounts do not match the account balances",
+                    trial_balance.mismatches.len()
+                )));
+            }
+        }
+
+        if options.command == Command::Reconcile {
+            let expected = File::open(&options.args[1]).map_err(PaymentError::source_error(
+                format!("Could not open {}", options.args[1]),
+            ))?;
+            let expected = read_expected_balances(BufReader::new(expected))?;
+            let reconciliation = ReconciliationReport::new(&account_service.accounts()?, &expected);
+            let mut report = Vec::new();
+            reconciliation.write_csv(&mut report)?;
+            write_output(options, &report).await?;
+            if !reconciliation.is_reconciled() {
+                return Err(PaymentError::VerificationError(format!(
+                    "{} discrepancies with the expected balances",
+                    reconciliation.discrepancies.len()
+                )));
+            }
+            return Ok(());
+        }
+
+        match &options.output {
+            Some(output) => {
+                let mut report = Vec::new();
+                account_service.write_accounts(&mut report, options.format, &options.report)?;
+                uploadable_for_uri(&to_uri(output))?.upload(&report).await
+            }
+            None => account_service.write_accounts(
+                &mut io::stdout().lock(),
+                options.format,
+                &options.report,
+            ),
+        }
+    }
+
+    async fn process(
+        &self,
+        options: &Options,
+        ingestion_service: IngestionService,
+        uri: &str,
+    ) -> Result<(), PaymentError> {
+        let ingestion_service = match &options.checkpoint {
+            Some(checkpoint) => ingestion_service.with_checkpoints(CheckpointConfig::new(
+                Path::new(checkpoint),
+                options
+                    .checkpoint_interval
+                    .unwrap_or(DEFAULT_CHECKPOINT_INTERVAL),
+            )),
+            None => ingestion_service,
+        };
+        if options.resume {
+            ingestion_service.resume_payments_csv(uri).await?;
+        } else {
+            ingestion_service.submit_payments_csv(uri).await?;
+        }
+
+        ingestion_service.run().await;
+        for result in ingestion_service.shutdown_gracefully().await {
+            result?;
+        }
+        Ok(())
+    }
+}
+
+async fn write_stats(
+    options: &Options,
+    account_service: &AccountService,
+) -> Result<(), PaymentError> {
+    if !options.stats && options.stats_json.is_none() {
+        return Ok(());
+    }
+    let stats = account_service.run_stats()?;
+    if options.stats {
+        stats.write_text(&mut io::stderr().lock())?;
+    }
+    if let Some(stats_uri) = &options.stats_json {
+        let mut contents = Vec::new();
+        stats.write_json(&mut contents)?;
+        uploadable_for_uri(&to_uri(stats_uri))?
+            .upload(&contents)
+            .await?;
+    }
+    Ok(())
+}
+
+// The config has to be loaded before the other flags are parsed, wherever it is given
+fn config_path(args: &[String]) -> Result<Option<String>, PaymentError> {
+    match args.iter().position(|arg| arg == "--config") {
+        Some(i) => flag_value("--config", args.get(i + 1)).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn flag_value(flag: &str, value: Option<&String>) -> Result<String, PaymentError> {
+    value
+        .cloned()
+        .ok_or_else(|| PaymentError::CliError(format!("Missing value for {}", flag)))
+}
+
+fn parse_flag_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, PaymentError> {
+    flag_value(flag, value)?
+        .parse()
+        .map_err(|_| PaymentError::CliError(format!("Invalid value for {}", flag)))
+}
+
+async fn write_output(options: &Options, contents: &[u8]) -> Result<(), PaymentError> {
+    match &options.output {
+        Some(output) => uploadable_for_uri(&to_uri(output))?.upload(contents).await,
+        None => write_stdout(contents),
+    }
+}
+
+fn write_stdout(contents: &[u8]) -> Result<(), PaymentError> {
+    io::stdout()
+        .lock()
+        .write_all(contents)
+        .map_err(PaymentError::storage_error("Could not write to stdout"))
+}
+
+// Plain paths are treated as local files
+fn to_uri(path_or_uri: &str) -> String {
+    if path_or_uri.contains("://") {
+        path_or_uri.to_string()
+    } else {
+        format!("file://{}", path_or_uri)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("payments_engine")
+            .chain(args.iter().cloned())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let options = Options::parse(&args(&["transactions.csv"])).unwrap();
+        assert_eq!(options.command, Command::Process);
+        assert_eq!(options.args, vec!["transactions.csv"]);
+
+        let options = Options::parse(&args(&["reconcile", "in.csv", "expected.csv"])).unwrap();
+        assert_eq!(options.command, Command::Reconcile);
+        assert_eq!(options.args, vec!["in.csv", "expected.csv"]);
+
+        let options = Options::parse(&args(&["process", "in.csv", "--workers", "4"])).unwrap();
+        assert_eq!(options.workers, Some(4));
+
+        assert_eq!(
+            Options::parse(&args(&["--help"])).unwrap().command,
+            Command::Help
+        );
+        assert_eq!(
+            Options::parse(&args(&["help"])).unwrap().command,
+            Command::Help
+        );
+        assert_eq!(
+            Options::parse(&args(&["validate", "in.csv", "-h"]))
+                .unwrap()
+                .command,
+            Command::Help
+        );
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let dir = tempdir().unwrap();
+        let config = dir.path().join("engine.toml");
+        std::fs::write(
+            &config,
+            "workers = 2\n[output]\nformat = \"json\"\npath = \"accounts.json\"\n",
+        )
+        .unwrap();
+        let config = config.to_str().unwrap();
+
+        let options = Options::parse(&args(&["in.csv", "--config", config])).unwrap();
+        assert_eq!(options.workers, Some(2));
+        assert_eq!(options.format, OutputFormat::Json);
+        assert_eq!(options.output.as_deref(), Some("accounts.json"));
+
+        // Flags take precedence over the config
+        let options =
+            Options::parse(&args(&["--config", config, "in.csv", "--format", "table"])).unwrap();
+        assert_eq!(options.format, OutputFormat::Table);
+
+        // The output settings are for the accounts report, other reports keep writing csv to
+        // stdout
+        let options = Options::parse(&args(&[
+            "reconcile",
+            "in.csv",
+            "expected.csv",
+            "--config",
+            config,
+        ]))
+        .unwrap();
+        assert_eq!(options.format, OutputFormat::Csv);
+        assert!(options.output.is_none());
+        assert!(Options::parse(&args(&["diff", "a.csv", "b.csv", "--format", "json"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for invalid in [
+            vec![],
+            vec!["diff", "before.csv"],
+            vec!["in.csv", "--unknown"],
+            vec!["in.csv", "--workers", "0"],
+            vec!["in.csv", "--workers"],
+            vec!["inspect", "1"],
+            vec![
+                "inspect",
+                "1",
+                "--journal",
+                "journal.csv",
+                "--format",
+                "table",
+            ],
+            vec!["diff", "a.csv", "b.csv", "--format", "json"],
+            vec!["in.csv", "--verify", "accounts.snapshot"],
+        ] {
+            let error = Options::parse(&args(&invalid)).err().unwrap();
+            assert_eq!(exit_code(&error), 2, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(exit_code(&PaymentError::CliError("usage".to_string())), 2);
+        assert_eq!(
+            exit_code(&PaymentError::VerificationError("failed".to_string())),
+            1
+        );
+        assert_eq!(
+            exit_code(&PaymentError::ParseError("bad row".to_string())),
+            3
+        );
+        let missing = PaymentError::source_error("Could not open in.csv")(io::Error::new(
+            io::ErrorKind::NotFound,
+            "missing",
+        ));
+        assert_eq!(exit_code(&missing), 6);
+    }
+
+    #[tokio::test]
+    async fn test_validate_uri() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("transactions.csv");
+        let output = dir.path().join("dry_run.csv");
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,1.0\nrefund,1,2,1.0\n",
+        )
+        .unwrap();
+
+        let result = CLI::new()
+            .execute(args(&[
+                "validate",
+                &format!("file://{}", input.display()),
+                "--output",
+                output.to_str().unwrap(),
+            ]))
+            .await;
+        assert_eq!(exit_code(&result.err().unwrap()), 1);
+        let report = std::fs::read_to_string(&output).unwrap();
+        assert!(report.starts_with("line,result,reason\n2,applied,\n3,invalid,"));
+        assert_eq!(report.lines().count(), 3);
     }
 }
@@ -0,0 +1,247 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::observer::{AccountEvent, AccountObserver, Balances};
+use crate::engine::payments::{AccountService, TransactionOutcome};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+const AUDIT_HEADER: &str = "client,tx,type,available_before,held_before,locked_before,\
+available_after,held_after,locked_after,reason";
+
+// What one processed transaction did to one account. Rejected transactions are recorded
+// too, with the same balances before and after and the rejection as the reason.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditRecord {
+    pub client_id: u16,
+    pub transaction_id: u32,
+    pub transaction_type: String,
+    pub before: Balances,
+    pub after: Balances,
+    // "applied" or the rejection reason
+    pub reason: String,
+}
+
+impl AuditRecord {
+    pub fn new(event: &AccountEvent) -> Self {
+        Self {
+            client_id: event.client_id,
+            transaction_id: event.transaction.transaction_id(),
+            transaction_type: event.transaction.transaction_type().to_string(),
+            before: event.before,
+            after: event.after,
+            reason: match event.outcome {
+                TransactionOutcome::Applied => "applied".to_string(),
+                TransactionOutcome::Rejected(reason) => reason.to_string(),
+            },
+        }
+    }
+
+    fn parse(line: &str) -> Result<Self, PaymentError> {
+        let fields: Vec<&str> = line.split(',').collect();
+        if fields.len() != 10 {
+            return Err(invalid_record(line));
+        }
+        let balances = |offset: usize| -> Result<Balances, PaymentError> {
+            Ok(Balances {
+                available: parse_field(fields[offset], line)?,
+                held: parse_field(fields[offset + 1], line)?,
+                locked: parse_field(fields[offset + 2], line)?,
+            })
+        };
+        Ok(Self {
+            client_id: parse_field(fields[0], line)?,
+            transaction_id: parse_field(fields[1], line)?,
+            transaction_type: fields[2].to_string(),
+            before: balances(3)?,
+            after: balances(6)?,
+            reason: fields[9].to_string(),
+        })
+    }
+}
+
+// Append-only csv audit trail, registered as an observer of the account service
+pub struct FileAuditSubscriber {
+    writer: BufWriter<File>,
+}
+
+impl FileAuditSubscriber {
+    pub fn open(path: &Path) -> Result<Self, PaymentError> {
+        let context = format!("Could not open audit trail {}", path.display());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(PaymentError::storage_error(&context))?;
+        let empty = file
+            .metadata()
+            .map_err(PaymentError::storage_error(&context))?
+            .len()
+            == 0;
+        let mut writer = BufWriter::new(file);
+        if empty {
+            writeln!(writer, "{}", AUDIT_HEADER)
+                .and_then(|_| writer.flush())
+                .map_err(PaymentError::storage_error(format!(
+                    "Could not write audit header to {}",
+                    path.display()
+                )))?;
+        }
+        Ok(Self { writer })
+    }
+}
+
+impl AccountObserver for FileAuditSubscriber {
+    fn on_transaction(&mut self, event: &AccountEvent) -> Result<(), PaymentError> {
+        let record = AuditRecord::new(event);
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{},{},{}",
+            record.client_id,
+            record.transaction_id,
+            record.transaction_type,
+            record.before.available,
+            record.before.held,
+            record.before.locked,
+            record.after.available,
+            record.after.held,
+            record.after.locked,
+            record.reason
+        )
+        .and_then(|_| self.writer.flush())
+        .map_err(PaymentError::storage_error(format!(
+            "Could not write audit record for transaction {}",
+            record.transaction_id
+        )))
+    }
+
+    fn position(&mut self) -> Result<Option<u64>, PaymentError> {
+        self.writer
+            .flush()
+            .and_then(|_| self.writer.get_ref().metadata())
+            .map(|metadata| Some(metadata.len()))
+            .map_err(PaymentError::storage_error(
+                "Could not read audit trail position",
+            ))
+    }
+
+    fn truncate(&mut self, position: u64) -> Result<(), PaymentError> {
+        self.writer
+            .flush()
+            .and_then(|_| self.writer.get_ref().set_len(position))
+            .and_then(|_| self.writer.get_ref().sync_data())
+            .map_err(PaymentError::storage_error(format!(
+                "Could not truncate audit trail to {} bytes",
+                position
+            )))
+    }
+}
+
+// Replays the trail and checks that every record starts from the balances the previous
+// record of that client ended with, and that the last record of every client matches its
+// account. A client in the trail without an account is a mismatch too. The first record of
+// a client may start anywhere since the accounts can be loaded from a snapshot.
+pub fn verify_audit_trail(
+    path: &Path,
+    account_service: &AccountService,
+) -> Result<(), PaymentError> {
+    let mut balances: HashMap<u16, Balances> = HashMap::new();
+    let context = format!("Could not read audit trail {}", path.display());
+    let mut lines =
+        BufReader::new(File::open(path).map_err(PaymentError::storage_error(&context))?).lines();
+    lines
+        .next()
+        .transpose()
+        .map_err(PaymentError::storage_error(&context))?;
+    for (i, line) in lines.enumerate() {
+        let line = line.map_err(PaymentError::storage_error(&context))?;
+        let record = AuditRecord::parse(&line)?;
+        if let Some(previous) = balances.get(&record.client_id) {
+            if *previous != record.before {
+                return Err(PaymentError::VerificationError(format!(
+                    "Audit trail record {} for client {} does not follow from the previous one",
+                    i + 1,
+                    record.client_id
+                )));
+            }
+        }
+        balances.insert(record.client_id, record.after);
+    }
+
+    let mut mismatched = Vec::new();
+    for (client_id, balances) in balances {
+        let account = account_service.get_account(client_id)?;
+        if account.is_none_or(|account| balances != Balances::of(&account)) {
+            mismatched.push(client_id);
+        }
+    }
+    mismatched.sort_unstable();
+    if mismatched.is_empty() {
+        Ok(())
+    } else {
+        Err(PaymentError::VerificationError(format!(
+            "Audit trail does not match the accounts of clients {:?}",
+            mismatched
+        )))
+    }
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str, line: &str) -> Result<T, PaymentError> {
+    field.parse().map_err(|_| invalid_record(line))
+}
+
+fn invalid_record(line: &str) -> PaymentError {
+    PaymentError::ParseError(format!("Invalid audit record: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ledger;
+    use crate::engine::payments::Transaction;
+    use std::fs;
+    use std::str::FromStr;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_audit_trail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.csv");
+        let account_service = AccountService::new();
+        account_service.add_observer(Box::new(FileAuditSubscriber::open(&path).unwrap()));
+        for line in [
+            "deposit,1,1,10.0",
+            "withdrawal,1,2,20.0",
+            "dispute,1,1,",
+            "chargeback,1,1,",
+            "deposit,1,3,1.0",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "{}\n\
+                 1,1,deposit,0,0,false,10,0,false,applied\n\
+                 1,2,withdrawal,10,0,false,10,0,false,insufficient_funds\n\
+                 1,1,dispute,10,0,false,0,10,false,applied\n\
+                 1,1,chargeback,0,10,false,0,0,true,applied\n\
+                 1,3,deposit,0,0,true,0,0,true,account_locked\n",
+                AUDIT_HEADER
+            )
+        );
+        verify_audit_trail(&path, &account_service).unwrap();
+
+        let mut tampered = account_service.get_account(1).unwrap().unwrap();
+        tampered.available = ledger::to_units(5.0);
+        let tampered_service = AccountService::new();
+        tampered_service.restore(vec![Ok(tampered)]).unwrap();
+        assert!(verify_audit_trail(&path, &tampered_service).is_err());
+        assert!(verify_audit_trail(&path, &AccountService::new()).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use crate::engine::config::Config;
+use crate::engine::errors::PaymentError;
+use crate::engine::ingestion::{IngestionService, PaymentsQueue};
+use crate::engine::observer::AccountObserver;
+use crate::engine::payments::{Account, AccountService};
+use crate::engine::policy::Policy;
+use crate::engine::retention::Retention;
+use crate::engine::store::{AccountStore, InMemoryStore};
+
+// Sets up an `Engine`, everything is optional:
+//
+//   let engine = EngineBuilder::new()
+//       .with_workers(4)
+//       .with_queue_capacity(10_000)
+//       .with_source("file://day1.csv")
+//       .build()?;
+//   engine.run().await?;
+pub struct EngineBuilder {
+    workers: u8,
+    queue_capacity: Option<usize>,
+    store: Box<dyn AccountStore>,
+    policy: Policy,
+    retention: Retention,
+    sources: Vec<String>,
+    observers: Vec<Box<dyn AccountObserver>>,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self {
+            workers: 1,
+            queue_capacity: None,
+            store: Box::new(InMemoryStore::new()),
+            policy: Policy::default(),
+            retention: Retention::default(),
+            sources: Vec::new(),
+            observers: Vec::new(),
+        }
+    }
+
+    // Workers, policy and sources of a config file, the output settings are left to the caller
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.workers = config.workers.unwrap_or(self.workers);
+        self.policy = config.policy;
+        self.sources.extend(config.sources.iter().cloned());
+        self
+    }
+
+    pub fn with_workers(mut self, workers: u8) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    // Queued rows are processed whenever the queue reaches `capacity`, which bounds
+    // memory for large inputs. Unbounded by default.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    pub fn with_store(mut self, store: Box<dyn AccountStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    // Processed in the order they were added by `Engine::run`
+    pub fn with_source(mut self, uri: &str) -> Self {
+        self.sources.push(uri.to_string());
+        self
+    }
+
+    // Called after every transaction, e.g. a `FileAuditSubscriber` or a closure
+    pub fn with_observer(mut self, observer: Box<dyn AccountObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    pub fn build(self) -> Result<Engine, PaymentError> {
+        // Nothing would ever take the queued rows
+        if self.workers == 0 {
+            return Err(PaymentError::ConfigError(
+                "The engine needs at least 1 worker".to_string(),
+            ));
+        }
+        let account_service = AccountService::with_store(self.store);
+        account_service.set_policy(self.policy);
+        account_service.set_retention(self.retention);
+        for observer in self.observers {
+            account_service.add_observer(observer);
+        }
+
+        let payments_queue = match self.queue_capacity {
+            Some(capacity) => PaymentsQueue::with_capacity(capacity),
+            None => PaymentsQueue::new(),
+        };
+        let ingestion_service =
+            IngestionService::new(payments_queue, account_service.clone(), self.workers);
+        Ok(Engine {
+            ingestion_service,
+            account_service,
+            sources: self.sources,
+        })
+    }
+}
+
+// Owns ingestion and processing, the accounts can be read through `account_service`
+// while and after inputs are processed
+pub struct Engine {
+    ingestion_service: IngestionService,
+    account_service: AccountService,
+    sources: Vec<String>,
+}
+
+impl Engine {
+    pub fn ingestion_service(&self) -> &IngestionService {
+        &self.ingestion_service
+    }
+
+    pub fn account_service(&self) -> &AccountService {
+        &self.account_service
+    }
+
+    pub fn accounts(&self) -> Result<Vec<Account>, PaymentError> {
+        self.account_service.accounts()
+    }
+
+    // Queues the rows of `uri`, they are processed on `shutdown`
+    pub async fn submit(&self, uri: &str) -> Result<(), PaymentError> {
+        self.ingestion_service.submit_payments_csv(uri).await
+    }
+
+    // Processes every queued row and waits for the workers to finish. The engine can
+    // still be used afterwards.
+    pub async fn shutdown(&self) -> Result<(), PaymentError> {
+        self.ingestion_service.run().await;
+        for result in self.ingestion_service.shutdown_gracefully().await {
+            result?;
+        }
+        Ok(())
+    }
+
+    pub async fn process(&self, uri: &str) -> Result<(), PaymentError> {
+        self.submit(uri).await?;
+        self.shutdown().await
+    }
+
+    // Processes the configured sources in order
+    pub async fn run(&self) -> Result<(), PaymentError> {
+        for source in &self.sources {
+            self.process(source).await?;
+        }
+        Ok(())
+    }
+
+    // For callers that drive ingestion and processing themselves
+    pub fn into_parts(self) -> (IngestionService, AccountService) {
+        (self.ingestion_service, self.account_service)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_engine_builder() {
+        let mut day1 = NamedTempFile::new().unwrap();
+        day1.write_all("type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n".as_bytes())
+            .unwrap();
+        let mut day2 = NamedTempFile::new().unwrap();
+        day2.write_all("type,client,tx,amount\nwithdrawal,1,3,4.0\ndispute,1,3,\n".as_bytes())
+            .unwrap();
+
+        let engine = EngineBuilder::new()
+            .with_workers(2)
+            .with_queue_capacity(1)
+            .with_policy(Policy {
+                allow_withdrawal_disputes: false,
+                ..Policy::default()
+            })
+            .with_source(&format!("file://{}", day1.path().to_str().unwrap()))
+            .with_source(&format!("file://{}", day2.path().to_str().unwrap()))
+            .build()
+            .unwrap();
+        engine.run().await.unwrap();
+
+        let account = engine.account_service().get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 6.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(engine.accounts().unwrap().len(), 2);
+        assert_eq!(engine.account_service().run_stats().unwrap().rows_read, 4);
+
+        assert!(EngineBuilder::new().with_workers(0).build().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::AccountService;
+use crate::engine::snapshot::{write_account, SnapshotReader};
+use crate::engine::upload::write_atomically;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Written in the header, bumped whenever its layout changes
+const CHECKPOINT_VERSION: u32 = 3;
+
+#[derive(Clone, Debug)]
+pub struct CheckpointConfig {
+    pub path: PathBuf,
+    // Number of input lines processed between checkpoints
+    pub interval: u64,
+}
+
+impl CheckpointConfig {
+    pub fn new(path: &Path, interval: u64) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            interval,
+        }
+    }
+}
+
+// Position in a source file, saved along with the account state after every line up to
+// that position has been applied so processing can resume without re-applying anything
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub uri: String,
+    pub byte_offset: u64,
+    pub line_number: u64,
+    // Length of the journal at the checkpoint, the rows after it are journaled again on resume
+    pub journal_position: Option<u64>,
+    // Positions of the observers that can be rewound, see `AccountService::observer_positions`
+    pub observer_positions: Vec<u64>,
+}
+
+impl Checkpoint {
+    pub fn new(uri: &str, byte_offset: u64, line_number: u64) -> Self {
+        Self {
+            uri: uri.to_string(),
+            byte_offset,
+            line_number,
+            journal_position: None,
+            observer_positions: Vec::new(),
+        }
+    }
+
+    pub fn with_journal_position(mut self, journal_position: Option<u64>) -> Self {
+        self.journal_position = journal_position;
+        self
+    }
+
+    pub fn with_observer_positions(mut self, observer_positions: Vec<u64>) -> Self {
+        self.observer_positions = observer_positions;
+        self
+    }
+
+    // The accounts are written as they are read from the account service
+    pub fn save(&self, path: &Path, account_service: &AccountService) -> Result<(), PaymentError> {
+        write_atomically(path, |writer| {
+            // The journal position is left empty when there is no journal, the audit
+            // positions are separated by `;`
+            let observer_positions: Vec<String> = self
+                .observer_positions
+                .iter()
+                .map(|position| position.to_string())
+                .collect();
+            writeln!(
+                writer,
+                "checkpoint,{},{},{},{},{},{}",
+                CHECKPOINT_VERSION,
+                self.line_number,
+                self.byte_offset,
+                self.journal_position
+                    .map(|position| position.to_string())
+                    .unwrap_or_default(),
+                observer_positions.join(";"),
+                self.uri
+            )
+            .map_err(PaymentError::storage_error(format!(
+                "Could not write checkpoint header to {}",
+                path.display()
+            )))?;
+            account_service.for_each_account(|account| write_account(account, writer))
+        })
+    }
+
+    // Only reads the header, the accounts are loaded with `restore`
+    pub fn load(path: &Path) -> Result<Self, PaymentError> {
+        let (_, header) = read_header(path)?;
+
+        // The uri goes last since it is the only field that could contain a comma
+        let parts: Vec<&str> = header.trim_end().splitn(7, ',').collect();
+        if parts.len() != 7 || parts[0] != "checkpoint" {
+            return Err(PaymentError::ParseError(format!(
+                "Invalid checkpoint header: {}",
+                header.trim_end()
+            )));
+        }
+        if parts[1] != CHECKPOINT_VERSION.to_string() {
+            return Err(PaymentError::ParseError(format!(
+                "Unsupported checkpoint version: {}",
+                parts[1]
+            )));
+        }
+        let line_number = parts[2]
+            .parse::<u64>()
+            .map_err(|_| PaymentError::ParseError("Could not parse line number".to_string()))?;
+        let byte_offset = parts[3]
+            .parse::<u64>()
+            .map_err(|_| PaymentError::ParseError("Could not parse byte offset".to_string()))?;
+        let journal_position = match parts[4] {
+            "" => None,
+            position => Some(position.parse::<u64>().map_err(|_| {
+                PaymentError::ParseError("Could not parse journal position".to_string())
+            })?),
+        };
+        let observer_positions = parts[5]
+            .split(';')
+            .filter(|position| !position.is_empty())
+            .map(|position| {
+                position.parse::<u64>().map_err(|_| {
+                    PaymentError::ParseError("Could not parse audit position".to_string())
+                })
+            })
+            .collect::<Result<Vec<u64>, PaymentError>>()?;
+
+        Ok(Self {
+            uri: parts[6].to_string(),
+            byte_offset,
+            line_number,
+            journal_position,
+            observer_positions,
+        })
+    }
+
+    // Replaces the state of the account service with the accounts saved in the checkpoint
+    // at `path`
+    pub fn restore(path: &Path, account_service: &AccountService) -> Result<(), PaymentError> {
+        let (reader, _) = read_header(path)?;
+        account_service.restore(SnapshotReader::new(reader))
+    }
+}
+
+// Opens the checkpoint at `path` and reads its header line, the reader is left at the
+// first account
+fn read_header(path: &Path) -> Result<(BufReader<File>, String), PaymentError> {
+    let context = format!("Could not read checkpoint {}", path.display());
+    let mut reader =
+        BufReader::new(File::open(path).map_err(PaymentError::storage_error(&context))?);
+    let mut header = String::new();
+    reader
+        .read_line(&mut header)
+        .map_err(PaymentError::storage_error(context))?;
+    Ok((reader, header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ledger;
+    use crate::engine::payments::Account;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("payments.checkpoint");
+        let account_service = AccountService::new();
+        let mut account = Account::new(&7);
+        account.available = ledger::to_units(1.5);
+        account.locked = true;
+        account_service.restore(vec![Ok(account)]).unwrap();
+
+        Checkpoint::new("file://a,b.csv", 42, 3)
+            .save(&path, &account_service)
+            .unwrap();
+        let checkpoint = Checkpoint::load(&path).unwrap();
+
+        assert_eq!(checkpoint.uri, "file://a,b.csv");
+        assert_eq!(checkpoint.byte_offset, 42);
+        assert_eq!(checkpoint.line_number, 3);
+        assert_eq!(checkpoint.journal_position, None);
+        assert!(checkpoint.observer_positions.is_empty());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let restored = AccountService::new();
+        Checkpoint::restore(&path, &restored).unwrap();
+        let account = restored.get_account(7).unwrap().unwrap();
+        assert_eq!(account.available(), 1.5);
+        assert!(account.locked());
+
+        Checkpoint::new("file://a,b.csv", 42, 3)
+            .with_journal_position(Some(100))
+            .with_observer_positions(vec![20, 30])
+            .save(&path, &account_service)
+            .unwrap();
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.uri, "file://a,b.csv");
+        assert_eq!(checkpoint.journal_position, Some(100));
+        assert_eq!(checkpoint.observer_positions, vec![20, 30]);
+
+        // Checkpoints written with a different layout are refused
+        std::fs::write(&path, "checkpoint,0,3,42,file://a.csv\n").unwrap();
+        assert!(Checkpoint::load(&path).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::policy::Policy;
+use crate::engine::report::{OutputFormat, ReportOptions};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use toml::{Table, Value};
+
+// Engine settings read from a toml file, e.g.
+//
+//   workers = 4
+//   sources = ["day1.csv", "day2.csv"]
+//
+//   [policy]
+//   allow_withdrawal_disputes = true
+//   locked_accounts_reject_all = true
+//   rounding = "none"
+//
+//   [output]
+//   format = "json"
+//   sort = "balance"
+//   only_locked = false
+//   only_non_zero = true
+//   path = "accounts.json"
+//
+// Every key is optional, unknown keys are an error so typos don't go unnoticed.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub workers: Option<u8>,
+    // Inputs processed in order, as paths or uris
+    pub sources: Vec<String>,
+    pub policy: Policy,
+    pub format: Option<OutputFormat>,
+    pub report: ReportOptions,
+    pub output: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, PaymentError> {
+        let contents = fs::read_to_string(path).map_err(PaymentError::source_error(format!(
+            "Could not read config {}",
+            path.display()
+        )))?;
+        Self::from_str(&contents)
+    }
+}
+
+impl FromStr for Config {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let table: Table = s
+            .parse()
+            .map_err(|e| PaymentError::ConfigError(format!("Invalid toml: {}", e)))?;
+
+        let mut config = Config::default();
+        for (key, value) in &table {
+            match key.as_str() {
+                "workers" => {
+                    config.workers = Some(
+                        value
+                            .as_integer()
+                            .and_then(|workers| u8::try_from(workers).ok())
+                            .filter(|workers| *workers > 0)
+                            .ok_or_else(|| invalid(key, "an integer between 1 and 255"))?,
+                    )
+                }
+                "sources" => {
+                    config.sources = value
+                        .as_array()
+                        .and_then(|sources| {
+                            sources
+                                .iter()
+                                .map(|source| source.as_str().map(String::from))
+                                .collect()
+                        })
+                        .ok_or_else(|| invalid(key, "an array of strings"))?
+                }
+                "policy" => parse_policy(section(key, value)?, &mut config.policy)?,
+                "output" => parse_output(section(key, value)?, &mut config)?,
+                _ => return Err(unknown(key)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn parse_policy(table: &Table, policy: &mut Policy) -> Result<(), PaymentError> {
+    for (key, value) in table {
+        let key = format!("policy.{}", key);
+        match key.as_str() {
+            "policy.allow_withdrawal_disputes" => {
+                policy.allow_withdrawal_disputes = boolean(&key, value)?
+            }
+            "policy.locked_accounts_reject_all" => {
+                policy.locked_accounts_reject_all = boolean(&key, value)?
+            }
+            "policy.rounding" => policy.rounding = parsed(&key, value, "none, round or truncate")?,
+            _ => return Err(unknown(&key)),
+        }
+    }
+    Ok(())
+}
+
+fn parse_output(table: &Table, config: &mut Config) -> Result<(), PaymentError> {
+    for (key, value) in table {
+        let key = format!("output.{}", key);
+        match key.as_str() {
+            "output.format" => {
+                config.format = Some(parsed(&key, value, "csv, json, jsonl or table")?)
+            }
+            "output.sort" => config.report.sort = parsed(&key, value, "client or balance")?,
+            "output.only_locked" => config.report.only_locked = boolean(&key, value)?,
+            "output.only_non_zero" => config.report.only_non_zero = boolean(&key, value)?,
+            "output.path" => config.output = Some(string(&key, value)?.to_string()),
+            _ => return Err(unknown(&key)),
+        }
+    }
+    Ok(())
+}
+
+fn section<'a>(key: &str, value: &'a Value) -> Result<&'a Table, PaymentError> {
+    value.as_table().ok_or_else(|| invalid(key, "a table"))
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, PaymentError> {
+    value.as_bool().ok_or_else(|| invalid(key, "true or false"))
+}
+
+fn string<'a>(key: &str, value: &'a Value) -> Result<&'a str, PaymentError> {
+    value.as_str().ok_or_else(|| invalid(key, "a string"))
+}
+
+fn parsed<T: FromStr>(key: &str, value: &Value, expected: &str) -> Result<T, PaymentError> {
+    string(key, value)?
+        .parse()
+        .map_err(|_| invalid(key, expected))
+}
+
+fn invalid(key: &str, expected: &str) -> PaymentError {
+    PaymentError::ConfigError(format!("Invalid value for {}, expected {}", key, expected))
+}
+
+fn unknown(key: &str) -> PaymentError {
+    PaymentError::ConfigError(format!("Unknown config key: {}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::policy::Rounding;
+    use crate::engine::report::SortOrder;
+
+    #[test]
+    fn test_config() {
+        let config = Config::from_str(
+            "workers = 4\n\
+             sources = [\"day1.csv\", \"s3://bucket/day2.csv\"]\n\
+             [policy]\n\
+             allow_withdrawal_disputes = false\n\
+             rounding = \"truncate\"\n\
+             [output]\n\
+             format = \"json\"\n\
+             sort = \"balance\"\n\
+             only_non_zero = true\n",
+        )
+        .unwrap();
+        assert_eq!(config.workers, Some(4));
+        assert_eq!(config.sources, vec!["day1.csv", "s3://bucket/day2.csv"]);
+        assert!(!config.policy.allow_withdrawal_disputes);
+        assert!(config.policy.locked_accounts_reject_all);
+        assert_eq!(config.policy.rounding, Rounding::Truncate);
+        assert_eq!(config.format, Some(OutputFormat::Json));
+        assert_eq!(config.report.sort, SortOrder::Balance);
+        assert!(config.report.only_non_zero);
+        assert!(config.output.is_none());
+    }
+
+    fn error(toml: &str) -> String {
+        match Config::from_str(toml) {
+            Err(PaymentError::ConfigError(message)) => message,
+            other => panic!("Expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert_eq!(
+            error("[policy]\nallow_chargebacks = true\n"),
+            "Unknown config key: policy.allow_chargebacks"
+        );
+        assert_eq!(error("worker = 4\n"), "Unknown config key: worker");
+        assert_eq!(
+            error("workers = 0\n"),
+            "Invalid value for workers, expected an integer between 1 and 255"
+        );
+        assert_eq!(
+            error("[output]\nformat = \"xml\"\n"),
+            "Invalid value for output.format, expected csv, json, jsonl or table"
+        );
+        assert_eq!(
+            error("policy = 1\n"),
+            "Invalid value for policy, expected a table"
+        );
+        assert!(error("workers = \n").starts_with("Invalid toml"));
+    }
+}
@@ -0,0 +1,218 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::ledger;
+use crate::engine::payments::Account;
+use crate::engine::reconcile::{pair_by_client, read_expected_balances, round};
+use crate::engine::snapshot::read_snapshot;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+// Loads either a csv account report or a snapshot, told apart by the report header
+pub fn read_accounts(path: &Path) -> Result<Vec<Account>, PaymentError> {
+    let contents = fs::read_to_string(path).map_err(PaymentError::source_error(format!(
+        "Could not read {}",
+        path.display()
+    )))?;
+    if contents.starts_with("client,") {
+        Ok(read_expected_balances(contents.as_bytes())?
+            .into_iter()
+            .map(|balance| {
+                let mut account = Account::new(&balance.client_id);
+                account.available = ledger::to_units(balance.available);
+                account.held = ledger::to_units(balance.held);
+                account.locked = balance.locked;
+                account
+            })
+            .collect())
+    } else {
+        read_snapshot(contents.as_bytes())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldChange {
+    pub field: String,
+    pub before: String,
+    pub after: String,
+    // after - before, only for balances
+    pub delta: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccountChange {
+    pub client_id: u16,
+    pub changes: Vec<FieldChange>,
+}
+
+// Differences between two sets of accounts, each list sorted by client id so the
+// order the accounts were read in doesn't matter
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccountDiff {
+    pub added: Vec<Account>,
+    pub removed: Vec<Account>,
+    pub changed: Vec<AccountChange>,
+}
+
+impl AccountDiff {
+    pub fn new(before: &[Account], after: &[Account]) -> Self {
+        let clients = pair_by_client(
+            before,
+            |account| account.client_id,
+            after,
+            |account| account.client_id,
+        );
+
+        let mut diff = Self::default();
+        for (client_id, pair) in clients {
+            match pair {
+                (Some(before), Some(after)) => {
+                    let changes = field_changes(before, after);
+                    if !changes.is_empty() {
+                        diff.changed.push(AccountChange { client_id, changes });
+                    }
+                }
+                (Some(before), None) => diff.removed.push(before.clone()),
+                (None, Some(after)) => diff.added.push(after.clone()),
+                (None, None) => {}
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // One row per added or removed client and per changed field
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "client,change,field,before,after,delta").map_err(write_error)?;
+        for account in &self.added {
+            writeln!(writer, "{},added,,,{},", account.client_id, account.total())
+                .map_err(write_error)?;
+        }
+        for account in &self.removed {
+            writeln!(
+                writer,
+                "{},removed,,{},,",
+                account.client_id,
+                account.total()
+            )
+            .map_err(write_error)?;
+        }
+        for account_change in &self.changed {
+            for change in &account_change.changes {
+                writeln!(
+                    writer,
+                    "{},changed,{},{},{},{}",
+                    account_change.client_id,
+                    change.field,
+                    change.before,
+                    change.after,
+                    change
+                        .delta
+                        .map(|delta| delta.to_string())
+                        .unwrap_or_default()
+                )
+                .map_err(write_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn field_changes(before: &Account, after: &Account) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    for (field, before, after) in [
+        ("available", before.available(), after.available()),
+        ("held", before.held(), after.held()),
+        ("total", before.total(), after.total()),
+    ] {
+        if before != after {
+            changes.push(FieldChange {
+                field: field.to_string(),
+                before: before.to_string(),
+                after: after.to_string(),
+                delta: Some(round(after - before)),
+            });
+        }
+    }
+    if before.locked() != after.locked() {
+        changes.push(FieldChange {
+            field: "locked".to_string(),
+            before: before.locked().to_string(),
+            after: after.locked().to_string(),
+            delta: None,
+        });
+    }
+    changes
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the snapshot diff")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::snapshot::write_snapshot;
+    use tempfile::tempdir;
+
+    fn account(client_id: u16, available: f32, locked: bool) -> Account {
+        let mut account = Account::new(&client_id);
+        account.available = ledger::to_units(available);
+        account.locked = locked;
+        account
+    }
+
+    #[test]
+    fn test_diff_report_and_snapshot() {
+        let dir = tempdir().unwrap();
+        let report_path = dir.path().join("accounts.csv");
+        fs::write(
+            &report_path,
+            "client,available,held,total,locked\n\
+             3,1,0,1,false\n\
+             1,2.5,0,2.5,false\n\
+             2,4,0,4,false\n",
+        )
+        .unwrap();
+        let snapshot_path = dir.path().join("accounts.snapshot");
+        let mut snapshot = Vec::new();
+        write_snapshot(
+            &[
+                account(4, 7.0, false),
+                account(1, 2.5, false),
+                account(2, 3.75, true),
+            ],
+            &mut snapshot,
+        )
+        .unwrap();
+        fs::write(&snapshot_path, snapshot).unwrap();
+
+        let before = read_accounts(&report_path).unwrap();
+        let after = read_accounts(&snapshot_path).unwrap();
+        assert!(AccountDiff::new(&after, &after).is_empty());
+
+        let diff = AccountDiff::new(&before, &after);
+        let mut csv = Vec::new();
+        diff.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,change,field,before,after,delta\n\
+             4,added,,,7,\n\
+             3,removed,,1,,\n\
+             2,changed,available,4,3.75,-0.25\n\
+             2,changed,total,4,3.75,-0.25\n\
+             2,changed,locked,false,true,\n"
+        );
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::from_str::<AccountDiff>(&serde_json::to_string(&diff).unwrap()).unwrap(),
+            diff
+        );
+    }
+}
@@ -1,9 +1,14 @@
 use crate::engine::errors::PaymentError;
 use async_trait::async_trait;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
 use std::str::FromStr;
 
+pub type Lines = Box<dyn Iterator<Item = std::io::Result<String>>>;
+
+// Each item is a line along with the byte offset just past the end of that line
+pub type OffsetLines = Box<dyn Iterator<Item = std::io::Result<(String, u64)>>>;
+
 pub enum UriSchemes {
     File,
     S3,
@@ -16,16 +21,38 @@ impl FromStr for UriSchemes {
         match s {
             "file" => Ok(UriSchemes::File),
             "s3" => Ok(UriSchemes::S3),
-            _ => Err(Self::Err::InvalidUriScheme(s.to_string())),
+            _ => Err(Self::Err::SourceError {
+                context: format!("Unsupported uri scheme: {}", s),
+                source: None,
+            }),
         }
     }
 }
 
+pub fn downloadable_for_uri(uri: &str) -> Result<Box<dyn Downloadable>, PaymentError> {
+    let uri_parts: Vec<&str> = uri.split("://").collect();
+    if uri_parts.len() != 2 {
+        return Err(PaymentError::SourceError {
+            context: format!("Invalid uri: {}", uri),
+            source: None,
+        });
+    }
+    let scheme = UriSchemes::from_str(uri_parts[0])?;
+    let path = uri_parts[1];
+
+    let downloadable: Box<dyn Downloadable> = match scheme {
+        UriSchemes::File => Box::new(LocalFile::new(path)),
+        UriSchemes::S3 => Box::new(S3File::new()),
+    };
+    Ok(downloadable)
+}
+
 #[async_trait]
 pub trait Downloadable {
-    async fn download(
-        &self,
-    ) -> Result<Box<dyn Iterator<Item = std::io::Result<String>>>, PaymentError>;
+    async fn download(&self) -> Result<Lines, PaymentError>;
+
+    // Used for resuming from a checkpoint, starts reading at the given byte offset
+    async fn download_from(&self, offset: u64) -> Result<OffsetLines, PaymentError>;
 }
 
 pub struct LocalFile {
@@ -40,6 +67,7 @@ impl LocalFile {
     }
 }
 
+#[derive(Default)]
 pub struct S3File {}
 
 impl S3File {
@@ -50,21 +78,115 @@ impl S3File {
 
 #[async_trait]
 impl Downloadable for LocalFile {
-    async fn download(
-        &self,
-    ) -> Result<Box<dyn Iterator<Item = std::io::Result<String>>>, PaymentError> {
-        let file = File::open(&self.file_path)
-            .map_err(|e| PaymentError::FileDownloadError(e.to_string()))?;
+    async fn download(&self) -> Result<Lines, PaymentError> {
+        let file = File::open(&self.file_path).map_err(PaymentError::source_error(format!(
+            "Could not open {}",
+            self.file_path
+        )))?;
         let lines = BufReader::new(file).lines();
         Ok(Box::new(lines))
     }
+
+    async fn download_from(&self, offset: u64) -> Result<OffsetLines, PaymentError> {
+        let mut file = File::open(&self.file_path).map_err(PaymentError::source_error(format!(
+            "Could not open {}",
+            self.file_path
+        )))?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(PaymentError::source_error(format!(
+                "Could not seek to byte {} of {}",
+                offset, self.file_path
+            )))?;
+        Ok(Box::new(ByteOffsetLines {
+            reader: BufReader::new(file),
+            offset,
+        }))
+    }
 }
 
 #[async_trait]
 impl Downloadable for S3File {
-    async fn download(
-        &self,
-    ) -> Result<Box<dyn Iterator<Item = std::io::Result<String>>>, PaymentError> {
-        unimplemented!()
+    async fn download(&self) -> Result<Lines, PaymentError> {
+        Err(s3_not_implemented("downloads"))
+    }
+
+    async fn download_from(&self, _offset: u64) -> Result<OffsetLines, PaymentError> {
+        Err(s3_not_implemented("downloads"))
+    }
+}
+
+pub(crate) fn s3_not_implemented(operation: &str) -> PaymentError {
+    PaymentError::SourceError {
+        context: format!("S3 {} are not implemented", operation),
+        source: None,
+    }
+}
+
+// Like `BufRead::lines` but keeps track of how many bytes have been consumed so far,
+// `lines` strips the line endings so the offset can't be recovered from its output
+struct ByteOffsetLines<R: BufRead> {
+    reader: R,
+    offset: u64,
+}
+
+impl<R: BufRead> Iterator for ByteOffsetLines<R> {
+    type Item = std::io::Result<(String, u64)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(bytes_read) => {
+                self.offset += bytes_read as u64;
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+                Some(Ok((line, self.offset)))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn test_download_from_offset() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all("header\r\nfoo\nbar".as_bytes()).unwrap();
+        let local_file = LocalFile::new(file.path().to_str().unwrap());
+
+        let lines: Vec<(String, u64)> = local_file
+            .download_from(0)
+            .await
+            .unwrap()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ("header".to_string(), 8),
+                ("foo".to_string(), 12),
+                ("bar".to_string(), 15)
+            ]
+        );
+
+        let lines: Vec<(String, u64)> = local_file
+            .download_from(8)
+            .await
+            .unwrap()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![("foo".to_string(), 12), ("bar".to_string(), 15)]
+        );
     }
 }
@@ -0,0 +1,147 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::{Account, AccountService, Transaction, TransactionOutcome};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+// What would happen to one row of the input
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DryRunResult {
+    Applied,
+    Rejected(String),
+    // The row couldn't be parsed into a transaction
+    Invalid(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DryRunEntry {
+    pub line_number: u64,
+    pub row: String,
+    pub result: DryRunResult,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DryRunReport {
+    pub entries: Vec<DryRunEntry>,
+    // Every account as it would be after the input was processed
+    pub projected: Vec<Account>,
+}
+
+impl DryRunReport {
+    // Parses and processes every row of a csv input against a scratch copy of the
+    // accounts, nothing is committed to `account_service` or its persistence
+    pub fn new<I>(account_service: &AccountService, lines: I) -> Result<Self, PaymentError>
+    where
+        I: IntoIterator<Item = io::Result<String>>,
+    {
+        let scratch = account_service.scratch_copy()?;
+        let mut entries = Vec::new();
+        for (i, row) in lines.into_iter().enumerate().skip(1) {
+            let row = row.map_err(PaymentError::source_error(format!(
+                "Could not read line {} of the input",
+                i + 1
+            )))?;
+            let result = match Transaction::from_str(&row) {
+                Ok(transaction) => match scratch.apply(transaction)? {
+                    TransactionOutcome::Applied => DryRunResult::Applied,
+                    TransactionOutcome::Rejected(reason) => {
+                        DryRunResult::Rejected(reason.to_string())
+                    }
+                },
+                Err(error) => DryRunResult::Invalid(error.to_string()),
+            };
+            entries.push(DryRunEntry {
+                line_number: i as u64 + 1,
+                row,
+                result,
+            });
+        }
+
+        // Only the balances are kept, in client id order
+        let mut projected = Vec::new();
+        scratch.for_each_account(|account| {
+            projected.push(account.balances());
+            Ok(())
+        })?;
+        Ok(Self { entries, projected })
+    }
+
+    pub fn invalid_rows(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.result, DryRunResult::Invalid(_)))
+            .count()
+    }
+
+    // One line per row of the input: `line,result,reason`
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "line,result,reason").map_err(write_error)?;
+        for entry in &self.entries {
+            let (result, reason) = match &entry.result {
+                DryRunResult::Applied => ("applied", ""),
+                DryRunResult::Rejected(reason) => ("rejected", reason.as_str()),
+                DryRunResult::Invalid(error) => ("invalid", error.as_str()),
+            };
+            writeln!(
+                writer,
+                "{},{},{}",
+                entry.line_number,
+                result,
+                reason.replace(',', ";")
+            )
+            .map_err(write_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the dry run report")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dry_run_does_not_commit() {
+        let account_service = AccountService::new();
+        account_service
+            .apply(Transaction::from_str("deposit,1,1,5.0").unwrap())
+            .unwrap();
+
+        let input = "type,client,tx,amount\n\
+                     withdrawal,1,2,2.0\n\
+                     withdrawal,1,3,10.0\n\
+                     refund,1,4,1.0\n\
+                     dispute,1,1,\n\
+                     deposit,2,5,1.5\n";
+        let lines = input.lines().map(|line| Ok(line.to_string()));
+        let report = DryRunReport::new(&account_service, lines).unwrap();
+        assert_eq!(report.invalid_rows(), 1);
+
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "line,result,reason\n\
+             2,applied,\n\
+             3,rejected,insufficient_funds\n\
+             4,invalid,Invalid transaction type: refund\n\
+             5,applied,\n\
+             6,applied,\n"
+        );
+        assert_eq!(report.projected.len(), 2);
+        assert_eq!(report.projected[0].available(), -2.0);
+        assert_eq!(report.projected[0].held(), 5.0);
+        assert_eq!(report.projected[1].total(), 1.5);
+
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 5.0);
+        assert!(!account.transactions[&1].under_dispute);
+        assert!(account_service.get_account(2).unwrap().is_none());
+    }
+}
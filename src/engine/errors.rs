@@ -1,15 +1,107 @@
-use std::io::Error;
+use std::error::Error;
+use std::fmt;
+use std::io;
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum PaymentError {
-    InvalidUriScheme(String),
-    FileDownloadError(String),
-    PaymentProcessingError(String),
+    // A transaction row or one of the engine's own files couldn't be parsed
+    ParseError(String),
+    // Processing would break one of the engine's invariants, e.g. unbalanced ledger entries
+    PolicyError(String),
+    // Accounts, journals, snapshots and other local state couldn't be read or written, or a
+    // report couldn't be written out
+    StorageError {
+        context: String,
+        source: io::Error,
+    },
+    // A file given on the command line, like the input or the config, couldn't be read or
+    // downloaded, or an output couldn't be uploaded
+    SourceError {
+        context: String,
+        source: Option<io::Error>,
+    },
+    ConfigError(String),
     CliError(String),
+    // The result of a run doesn't match what it was checked against, e.g. a reconciliation
+    VerificationError(String),
+    // A worker task panicked or was cancelled
+    WorkerError(String),
 }
 
-impl From<std::io::Error> for PaymentError {
-    fn from(e: Error) -> Self {
-        PaymentError::FileDownloadError(e.to_string())
+impl PaymentError {
+    // Wraps an io error with what was being done, for `map_err`
+    pub fn storage_error(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| PaymentError::StorageError { context, source }
+    }
+
+    pub fn source_error(context: impl Into<String>) -> impl FnOnce(io::Error) -> Self {
+        let context = context.into();
+        move |source| PaymentError::SourceError {
+            context,
+            source: Some(source),
+        }
+    }
+
+    // Stable identifier of the kind of error, safe to match on in scripts and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            PaymentError::ParseError(_) => "parse",
+            PaymentError::PolicyError(_) => "policy",
+            PaymentError::StorageError { .. } => "storage",
+            PaymentError::SourceError { .. } => "source",
+            PaymentError::ConfigError(_) => "config",
+            PaymentError::CliError(_) => "cli",
+            PaymentError::VerificationError(_) => "verification",
+            PaymentError::WorkerError(_) => "worker",
+        }
+    }
+}
+
+impl fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaymentError::ParseError(message)
+            | PaymentError::PolicyError(message)
+            | PaymentError::ConfigError(message)
+            | PaymentError::CliError(message)
+            | PaymentError::VerificationError(message)
+            | PaymentError::WorkerError(message) => write!(f, "{}", message),
+            // The io error is left to `source` so it isn't printed twice
+            PaymentError::StorageError { context, .. }
+            | PaymentError::SourceError { context, .. } => write!(f, "{}", context),
+        }
+    }
+}
+
+impl Error for PaymentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PaymentError::StorageError { source, .. } => Some(source),
+            PaymentError::SourceError {
+                source: Some(source),
+                ..
+            } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_source() {
+        let error = PaymentError::storage_error("Could not open accounts")(io::Error::new(
+            io::ErrorKind::NotFound,
+            "missing",
+        ));
+        assert_eq!(error.to_string(), "Could not open accounts");
+        assert_eq!(error.code(), "storage");
+        assert_eq!(error.source().unwrap().to_string(), "missing");
+        assert!(PaymentError::ParseError("bad row".to_string())
+            .source()
+            .is_none());
     }
 }
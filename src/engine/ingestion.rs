@@ -1,25 +1,22 @@
-use crate::engine::download::{Downloadable, LocalFile, S3File, UriSchemes};
+use crate::engine::checkpoint::{Checkpoint, CheckpointConfig};
+use crate::engine::download::downloadable_for_uri;
 use crate::engine::errors::PaymentError;
 use crate::engine::payments::AccountService;
 use crate::engine::payments::PaymentsProcessor;
 use std::collections::vec_deque::VecDeque;
-use std::str::FromStr;
 use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 
-pub struct IngestionServiceInner {
-    pub payments_queue: PaymentsQueue,
-    pub account_service: AccountService,
-    pub num_workers: u8,
-    pub workers: Vec<JoinHandle<Result<(), PaymentError>>>,
-}
+type WorkerHandle = JoinHandle<Result<(), PaymentError>>;
 
 #[derive(Clone)]
 pub struct IngestionService {
     pub payments_queue: PaymentsQueue,
     pub account_service: AccountService,
     pub num_workers: u8,
-    pub workers: Arc<Mutex<Vec<JoinHandle<Result<(), PaymentError>>>>>,
+    pub workers: Arc<Mutex<Vec<WorkerHandle>>>,
+    pub checkpoints: Option<CheckpointConfig>,
 }
 
 impl IngestionService {
@@ -28,95 +25,418 @@ impl IngestionService {
         account_service: AccountService,
         num_workers: u8,
     ) -> Self {
+        payments_queue.set_partitions(num_workers as usize);
         Self {
             payments_queue,
             account_service,
             num_workers,
             workers: Arc::new(Mutex::new(Vec::new())),
+            checkpoints: None,
         }
     }
 
+    pub fn with_workers(mut self, num_workers: u8) -> Self {
+        self.payments_queue.set_partitions(num_workers as usize);
+        self.num_workers = num_workers;
+        self
+    }
+
+    pub fn with_checkpoints(mut self, checkpoints: CheckpointConfig) -> Self {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    // Starts the workers, they wait for queued rows until `shutdown_gracefully`. Does
+    // nothing if they are already running. Every worker takes the rows of its own partition
+    // of the queue so the rows of a client are applied one at a time and in order.
     pub async fn run(&self) {
-        for _ in 0..self.num_workers {
+        let mut workers = self.workers.lock().expect("Ignore lock poisoning");
+        if !workers.is_empty() {
+            return;
+        }
+        for partition in 0..self.num_workers as usize {
             let payments_queue_clone = self.payments_queue.clone();
             let account_service_clone = self.account_service.clone();
 
             let worker = tokio::spawn(async move {
                 PaymentsProcessor::new(payments_queue_clone, account_service_clone)
+                    .with_partition(partition)
                     .start()
                     .await
             });
-            self.workers.lock().expect("").push(worker);
+            workers.push(worker);
         }
     }
 
+    // Waits for every queued row to be applied, then stops the workers. They can be
+    // started again with `run` afterwards.
     pub async fn shutdown_gracefully(&self) -> Vec<Result<(), PaymentError>> {
-        let mut results = Vec::new();
-        for worker in self
+        // Take the handles out so the lock isn't held while awaiting
+        let workers: Vec<WorkerHandle> = self
             .workers
             .lock()
             .expect("Ignore lock poisoning")
-            .iter_mut()
-        {
+            .drain(..)
+            .collect();
+        if !workers.is_empty() {
+            self.payments_queue.drained().await;
+        }
+        self.payments_queue.close();
+
+        let mut results = Vec::new();
+        for worker in workers {
             match worker.await {
                 Ok(result) => results.push(result),
-                Err(join_error) => results.push(Err(PaymentError::PaymentProcessingError(
-                    format!("{:?}", join_error),
-                ))),
+                Err(join_error) => {
+                    results.push(Err(PaymentError::WorkerError(format!("{:?}", join_error))))
+                }
             }
         }
-        return results;
+        self.payments_queue.reopen();
+        results
     }
 
     pub async fn submit_payments_csv(&self, uri: &str) -> Result<(), PaymentError> {
-        let uri_parts: Vec<&str> = uri.split("://").collect();
-        let scheme = UriSchemes::from_str(uri_parts[0])?;
-        let path = uri_parts[1];
-
-        let downloadable: Box<dyn Downloadable> = match scheme {
-            UriSchemes::File => Box::new(LocalFile::new(path)),
-            UriSchemes::S3 => Box::new(S3File::new()),
-        };
+        if let Some(checkpoints) = &self.checkpoints {
+            return self.process_with_checkpoints(uri, checkpoints, 0, 0).await;
+        }
 
+        let downloadable = downloadable_for_uri(uri)?;
         for payment_string in downloadable.download().await?.skip(1) {
-            self.payments_queue.publish_transaction(payment_string?);
+            let payment_string = payment_string.map_err(PaymentError::source_error(format!(
+                "Could not read {}",
+                uri
+            )))?;
+            self.payments_queue.publish_transaction(payment_string);
+            self.account_service.record_rows_read(1);
+            if self.payments_queue.is_full() {
+                self.drain_queue().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Restores the accounts from the last checkpoint and continues processing right
+    // after the last line it covers. Starts from the beginning if there is no checkpoint yet.
+    pub async fn resume_payments_csv(&self, uri: &str) -> Result<(), PaymentError> {
+        let checkpoints = self.checkpoints.as_ref().ok_or_else(|| {
+            PaymentError::ConfigError("Checkpoints are not configured".to_string())
+        })?;
+        if !checkpoints.path.exists() {
+            return self.process_with_checkpoints(uri, checkpoints, 0, 0).await;
+        }
+
+        let checkpoint = Checkpoint::load(&checkpoints.path)?;
+        if checkpoint.uri != uri {
+            return Err(PaymentError::ConfigError(format!(
+                "Checkpoint was taken for {} not {}",
+                checkpoint.uri, uri
+            )));
+        }
+        Checkpoint::restore(&checkpoints.path, &self.account_service)?;
+        // The rows after the checkpoint are applied, journaled and audited again
+        if let Some(journal_position) = checkpoint.journal_position {
+            self.account_service.truncate_journal(journal_position)?;
+        }
+        self.account_service
+            .truncate_observers(&checkpoint.observer_positions)?;
+        self.process_with_checkpoints(
+            uri,
+            checkpoints,
+            checkpoint.byte_offset,
+            checkpoint.line_number,
+        )
+        .await
+    }
+
+    // Lines are processed in batches of `checkpoints.interval`, the queue is drained
+    // after every batch so the accounts are consistent with the recorded offset
+    async fn process_with_checkpoints(
+        &self,
+        uri: &str,
+        checkpoints: &CheckpointConfig,
+        mut byte_offset: u64,
+        mut line_number: u64,
+    ) -> Result<(), PaymentError> {
+        let downloadable = downloadable_for_uri(uri)?;
+        let mut pending = 0;
+
+        for line in downloadable.download_from(byte_offset).await? {
+            let (payment_string, end_offset) = line.map_err(PaymentError::source_error(
+                format!("Could not read {}", uri),
+            ))?;
+            line_number += 1;
+            byte_offset = end_offset;
+
+            // Skip the header
+            if line_number == 1 {
+                continue;
+            }
+
+            self.payments_queue.publish_transaction(payment_string);
+            self.account_service.record_rows_read(1);
+            pending += 1;
+            if pending >= checkpoints.interval {
+                self.drain_queue().await?;
+                self.save_checkpoint(uri, checkpoints, byte_offset, line_number)?;
+                pending = 0;
+            }
         }
 
+        self.drain_queue().await?;
+        self.save_checkpoint(uri, checkpoints, byte_offset, line_number)
+    }
+
+    // Waits for the queued rows to be applied, the workers keep running afterwards
+    async fn drain_queue(&self) -> Result<(), PaymentError> {
+        if self.num_workers == 0 {
+            return Err(PaymentError::ConfigError(
+                "No workers to process the queue".to_string(),
+            ));
+        }
+        self.run().await;
+        if self.payments_queue.drained().await {
+            return Ok(());
+        }
+        // A worker failed and closed the queue
+        for result in self.shutdown_gracefully().await {
+            result?;
+        }
         Ok(())
     }
+
+    fn save_checkpoint(
+        &self,
+        uri: &str,
+        checkpoints: &CheckpointConfig,
+        byte_offset: u64,
+        line_number: u64,
+    ) -> Result<(), PaymentError> {
+        Checkpoint::new(uri, byte_offset, line_number)
+            .with_journal_position(self.account_service.journal_position()?)
+            .with_observer_positions(self.account_service.observer_positions()?)
+            .save(&checkpoints.path, &self.account_service)
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct PaymentsQueue {
-    queue: Arc<Mutex<VecDeque<String>>>,
+    state: Arc<Mutex<QueueState>>,
+    // Wakes workers waiting for rows and callers waiting for the queue to drain
+    changed: Arc<Notify>,
+    // Publishing never blocks, the ingestion service drains the queue once it's full
+    capacity: Option<usize>,
+}
+
+struct QueueState {
+    // Rows are split by client id so all of a client's rows are taken by the same worker
+    partitions: Vec<VecDeque<String>>,
+    // A transfer touches the accounts of two partitions, so with more than one it waits here
+    // until every row before it is applied and the rows after it wait until it is
+    waiting: VecDeque<String>,
+    // Rows taken by a worker that haven't been applied yet
+    in_progress: usize,
+    // Workers stop taking rows once the queue is closed
+    closed: bool,
+}
+
+impl Default for QueueState {
+    fn default() -> Self {
+        Self {
+            partitions: vec![VecDeque::new()],
+            waiting: VecDeque::new(),
+            in_progress: 0,
+            closed: false,
+        }
+    }
 }
 
 impl PaymentsQueue {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
+            capacity: Some(capacity),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.capacity.is_some_and(|capacity| {
+            let state = self.state.lock().expect("Ignore lock poisoning");
+            state.partitions.iter().map(VecDeque::len).sum::<usize>() + state.waiting.len()
+                >= capacity
+        })
+    }
+
+    // One partition per worker, rows that are already queued move to their new partition
+    pub(crate) fn set_partitions(&self, count: usize) {
+        let mut state = self.state.lock().expect("Ignore lock poisoning");
+        let mut rows: Vec<String> = state
+            .partitions
+            .iter_mut()
+            .flat_map(|partition| partition.drain(..))
+            .collect();
+        rows.extend(state.waiting.drain(..));
+        state.partitions = (0..count.max(1)).map(|_| VecDeque::new()).collect();
+        for row in rows {
+            state.push(row);
         }
     }
 
     pub fn publish_transaction(&self, message: String) {
-        self.queue
+        self.state
             .lock()
             .expect("Ignore lock poisoning")
-            .push_back(message);
+            .push(message);
+        self.changed.notify_waiters();
     }
 
     pub fn get_transaction(&self) -> Option<String> {
-        self.queue
+        let mut state = self.state.lock().expect("Ignore lock poisoning");
+        state
+            .partitions
+            .iter_mut()
+            .find_map(|partition| partition.pop_front())
+            .or_else(|| state.waiting.pop_front())
+    }
+
+    // Waits for the next row of `partition`, None once the queue is closed. The row is in
+    // progress until the worker calls `done`.
+    pub(crate) async fn take_transaction(&self, partition: usize) -> Option<String> {
+        loop {
+            // Created before checking so a row published in between still wakes us up
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().expect("Ignore lock poisoning");
+                if state.closed {
+                    return None;
+                }
+                if let Some(row) = state.take(partition) {
+                    state.in_progress += 1;
+                    return Some(row);
+                }
+                // Rows released from behind a transfer can be for the other workers
+                if state.release_waiting() {
+                    self.changed.notify_waiters();
+                    continue;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    pub(crate) fn done(&self) {
+        self.state
             .lock()
             .expect("Ignore lock poisoning")
-            .pop_front()
+            .in_progress -= 1;
+        self.changed.notify_waiters();
+    }
+
+    // Waits until every row has been taken and applied. False if the queue was closed
+    // first, e.g. because a worker failed.
+    pub(crate) async fn drained(&self) -> bool {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let state = self.state.lock().expect("Ignore lock poisoning");
+                if state.closed {
+                    return false;
+                }
+                if state.is_idle() && state.waiting.is_empty() {
+                    return true;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.state.lock().expect("Ignore lock poisoning").closed = true;
+        self.changed.notify_waiters();
+    }
+
+    pub(crate) fn reopen(&self) {
+        self.state.lock().expect("Ignore lock poisoning").closed = false;
     }
 }
 
+impl QueueState {
+    fn push(&mut self, row: String) {
+        if !self.waiting.is_empty() || (self.partitions.len() > 1 && is_transfer(&row)) {
+            self.waiting.push_back(row);
+        } else {
+            let partition = partition_for(&row, self.partitions.len());
+            self.partitions[partition].push_back(row);
+        }
+    }
+
+    // The next row of `partition`, or the transfer at the front of the waiting rows once
+    // every row before it is applied
+    fn take(&mut self, partition: usize) -> Option<String> {
+        if let Some(row) = self
+            .partitions
+            .get_mut(partition)
+            .and_then(VecDeque::pop_front)
+        {
+            return Some(row);
+        }
+        if self.is_idle() && self.waiting.front().is_some_and(|row| is_transfer(row)) {
+            return self.waiting.pop_front();
+        }
+        None
+    }
+
+    // Moves the rows queued after an applied transfer to their partitions, up to the next
+    // transfer. True if any row was moved.
+    fn release_waiting(&mut self) -> bool {
+        if !self.is_idle() {
+            return false;
+        }
+        let mut released = false;
+        while let Some(row) = self.waiting.pop_front() {
+            if is_transfer(&row) {
+                self.waiting.push_front(row);
+                break;
+            }
+            let partition = partition_for(&row, self.partitions.len());
+            self.partitions[partition].push_back(row);
+            released = true;
+        }
+        released
+    }
+
+    // Every row taken so far is applied and nothing is left in the partitions
+    fn is_idle(&self) -> bool {
+        self.in_progress == 0 && self.partitions.iter().all(VecDeque::is_empty)
+    }
+}
+
+fn is_transfer(row: &str) -> bool {
+    row.split(',').next().map(str::trim) == Some("transfer")
+}
+
+// Partition of a row by its client id, the second field. Rows without one go to the first
+// partition, the worker fails to parse them anyway.
+fn partition_for(row: &str, partitions: usize) -> usize {
+    row.split(',')
+        .nth(1)
+        .and_then(|client_id| client_id.trim().parse::<u16>().ok())
+        .map_or(0, |client_id| client_id as usize % partitions)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::audit::{verify_audit_trail, FileAuditSubscriber};
+    use crate::engine::journal::{replay, Journal};
+    use crate::engine::payments::Transaction;
     use std::io::Write;
+    use std::str::FromStr;
     use tempfile::NamedTempFile;
 
     #[tokio::test]
@@ -138,4 +458,165 @@ mod tests {
         assert_eq!(payments_queue.get_transaction().unwrap(), "bar");
         assert_eq!(payments_queue.get_transaction().unwrap(), "baz");
     }
+
+    #[tokio::test]
+    async fn test_workers_apply_rows_of_a_client_in_order() {
+        let account_service = AccountService::new();
+        let payments_queue = PaymentsQueue::new();
+        let ingestion_service =
+            IngestionService::new(payments_queue.clone(), account_service.clone(), 4);
+        // Each withdrawal is only possible after the deposit before it
+        for transaction_id in 0..100 {
+            for client_id in 0..16 {
+                let row = if transaction_id % 2 == 0 {
+                    format!("deposit,{},{},1.0", client_id, transaction_id)
+                } else {
+                    format!("withdrawal,{},{},1.0", client_id, transaction_id)
+                };
+                payments_queue.publish_transaction(row);
+            }
+        }
+        payments_queue.publish_transaction("deposit,3,100,2.5".to_string());
+
+        ingestion_service.run().await;
+        for result in ingestion_service.shutdown_gracefully().await {
+            result.unwrap();
+        }
+        for client_id in 0..16 {
+            let account = account_service.get_account(client_id).unwrap().unwrap();
+            let expected = if client_id == 3 { 2.5 } else { 0.0 };
+            assert_eq!(account.available(), expected);
+        }
+        assert_eq!(account_service.run_stats().unwrap().applied, 100 * 16 + 1);
+    }
+
+    #[tokio::test]
+    async fn test_workers_apply_transfers_in_order() {
+        let account_service = AccountService::new();
+        let payments_queue = PaymentsQueue::new();
+        let ingestion_service =
+            IngestionService::new(payments_queue.clone(), account_service.clone(), 4);
+        // The destination of each transfer is in another partition and can only withdraw
+        // the funds once the transfer is applied
+        let mut transaction_id = 0;
+        for _ in 0..20 {
+            for client_id in 0..16 {
+                let destination_id = (client_id + 1) % 16;
+                for row in [
+                    format!("deposit,{},{},1.0", client_id, transaction_id),
+                    format!(
+                        "transfer,{},{},1.0,{}",
+                        client_id,
+                        transaction_id + 1,
+                        destination_id
+                    ),
+                    format!("withdrawal,{},{},1.0", destination_id, transaction_id + 2),
+                ] {
+                    payments_queue.publish_transaction(row);
+                }
+                transaction_id += 3;
+            }
+        }
+
+        ingestion_service.run().await;
+        for result in ingestion_service.shutdown_gracefully().await {
+            result.unwrap();
+        }
+        let stats = account_service.run_stats().unwrap();
+        assert_eq!(stats.applied, 20 * 16 * 3);
+        assert_eq!(stats.transferred(), 20.0 * 16.0);
+        for client_id in 0..16 {
+            let account = account_service.get_account(client_id).unwrap().unwrap();
+            assert_eq!(account.total(), 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = CheckpointConfig::new(&dir.path().join("checkpoint"), 2);
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n".as_bytes())
+            .unwrap();
+        let uri = format!("file://{}", file.path().to_str().unwrap());
+
+        let account_service = AccountService::new();
+        IngestionService::new(PaymentsQueue::new(), account_service.clone(), 1)
+            .with_checkpoints(checkpoints.clone())
+            .submit_payments_csv(&uri)
+            .await
+            .unwrap();
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            3.0
+        );
+
+        // Simulate dying before the rest of the file was processed
+        file.write_all("withdrawal,1,3,0.5\ndispute,1,1,\n".as_bytes())
+            .unwrap();
+
+        let resumed_account_service = AccountService::new();
+        IngestionService::new(PaymentsQueue::new(), resumed_account_service.clone(), 1)
+            .with_checkpoints(checkpoints.clone())
+            .resume_payments_csv(&uri)
+            .await
+            .unwrap();
+        let account = resumed_account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 1.5);
+        assert_eq!(account.held(), 1.0);
+
+        let checkpoint = Checkpoint::load(&checkpoints.path).unwrap();
+        assert_eq!(checkpoint.line_number, 5);
+    }
+
+    #[tokio::test]
+    async fn test_resume_with_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoints = CheckpointConfig::new(&dir.path().join("checkpoint"), 2);
+        let journal_path = dir.path().join("journal.csv");
+        let audit_path = dir.path().join("audit.csv");
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n".as_bytes())
+            .unwrap();
+        let uri = format!("file://{}", file.path().to_str().unwrap());
+
+        let account_service = AccountService::new();
+        account_service.set_journal(Journal::open(&journal_path).unwrap());
+        account_service.add_observer(Box::new(FileAuditSubscriber::open(&audit_path).unwrap()));
+        IngestionService::new(PaymentsQueue::new(), account_service.clone(), 1)
+            .with_checkpoints(checkpoints.clone())
+            .submit_payments_csv(&uri)
+            .await
+            .unwrap();
+
+        // Simulate dying after a row was applied and journaled but before the next checkpoint
+        file.write_all("deposit,1,3,4.0\n".as_bytes()).unwrap();
+        account_service
+            .process_transaction(Transaction::from_str("deposit,1,3,4.0").unwrap())
+            .await
+            .unwrap();
+
+        let resumed_account_service = AccountService::new();
+        resumed_account_service.set_journal(Journal::open(&journal_path).unwrap());
+        resumed_account_service
+            .add_observer(Box::new(FileAuditSubscriber::open(&audit_path).unwrap()));
+        IngestionService::new(PaymentsQueue::new(), resumed_account_service.clone(), 1)
+            .with_checkpoints(checkpoints)
+            .resume_payments_csv(&uri)
+            .await
+            .unwrap();
+
+        let replayed = AccountService::new();
+        replay(&journal_path, &replayed).unwrap();
+        assert_eq!(replayed.get_account(1).unwrap().unwrap().total(), 7.0);
+        // The row applied before dying is only in the audit trail once
+        verify_audit_trail(&audit_path, &resumed_account_service).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&audit_path)
+                .unwrap()
+                .lines()
+                .count(),
+            4
+        );
+    }
 }
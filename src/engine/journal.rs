@@ -0,0 +1,205 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::{AccountService, Transaction};
+use crate::engine::snapshot::SnapshotReader;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+const JOURNAL_HEADER: &str = "type,client,tx,amount,recorded_at";
+
+// Append-only record of every transaction handed to the account service. The journal is
+// written in the same format as the input csv so it can also be fed back into the engine,
+// followed by the time the transaction was processed which the csv parser ignores.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &Path) -> Result<Self, PaymentError> {
+        let context = format!("Could not open journal {}", path.display());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(PaymentError::storage_error(&context))?;
+        let len = file
+            .metadata()
+            .map_err(PaymentError::storage_error(&context))?
+            .len();
+        if len == 0 {
+            writeln!(file, "{}", JOURNAL_HEADER)
+                .and_then(|_| file.sync_data())
+                .map_err(PaymentError::storage_error(format!(
+                    "Could not write journal header to {}",
+                    path.display()
+                )))?;
+        }
+        Ok(Self { file })
+    }
+
+    // Only returns once the transaction has been flushed to disk
+    pub fn append(
+        &mut self,
+        transaction: &Transaction,
+        recorded_at: u64,
+    ) -> Result<(), PaymentError> {
+        writeln!(self.file, "{},{}", transaction, recorded_at)
+            .and_then(|_| self.file.sync_data())
+            .map_err(PaymentError::storage_error(format!(
+                "Could not journal transaction {}",
+                transaction.transaction_id
+            )))
+    }
+
+    // Length of the journal in bytes, recorded by checkpoints
+    pub fn position(&self) -> Result<u64, PaymentError> {
+        self.file
+            .metadata()
+            .map(|metadata| metadata.len())
+            .map_err(PaymentError::storage_error(
+                "Could not read journal position",
+            ))
+    }
+
+    // Drops everything written after `position`, e.g. the rows a resumed run applies again
+    pub fn truncate(&mut self, position: u64) -> Result<(), PaymentError> {
+        self.file
+            .set_len(position)
+            .and_then(|_| self.file.sync_data())
+            .map_err(PaymentError::storage_error(format!(
+                "Could not truncate journal to {} bytes",
+                position
+            )))
+    }
+}
+
+// Rebuilds state by applying every journaled transaction, in order, on top of whatever
+// state the account service already has. Transactions are applied at the time they were
+// recorded so the retention policy makes the same decisions as the original run.
+pub fn replay(journal_path: &Path, account_service: &AccountService) -> Result<(), PaymentError> {
+    let context = format!("Could not read journal {}", journal_path.display());
+    let reader =
+        BufReader::new(File::open(journal_path).map_err(PaymentError::storage_error(&context))?);
+    for line in reader.lines().skip(1) {
+        let line = line.map_err(PaymentError::storage_error(&context))?;
+        let (transaction, recorded_at) = parse_record(&line)?;
+        account_service.apply_at(transaction, recorded_at)?;
+    }
+    Ok(())
+}
+
+// A journaled transaction along with the time it was processed at
+pub(crate) fn parse_record(line: &str) -> Result<(Transaction, u64), PaymentError> {
+    let (row, recorded_at) = line
+        .rsplit_once(',')
+        .and_then(|(row, recorded_at)| Some((row, recorded_at.parse::<u64>().ok()?)))
+        .ok_or_else(|| PaymentError::ParseError(format!("Invalid journal record: {}", line)))?;
+    Ok((Transaction::from_str(row)?, recorded_at))
+}
+
+// Checks that the account service holds exactly the state recorded in the snapshot
+pub fn verify(account_service: &AccountService, snapshot_path: &Path) -> Result<(), PaymentError> {
+    // Both sides are read an account at a time, only the client ids are kept
+    let mut expected_clients = HashSet::new();
+    let mut mismatched = Vec::new();
+    let file = File::open(snapshot_path).map_err(PaymentError::storage_error(format!(
+        "Could not open snapshot {}",
+        snapshot_path.display()
+    )))?;
+    for expected in SnapshotReader::new(BufReader::new(file)) {
+        let expected = expected?;
+        if account_service.get_account(expected.client_id)?.as_ref() != Some(&expected) {
+            mismatched.push(expected.client_id);
+        }
+        expected_clients.insert(expected.client_id);
+    }
+    account_service.for_each_account(|account| {
+        if !expected_clients.contains(&account.client_id) {
+            mismatched.push(account.client_id);
+        }
+        Ok(())
+    })?;
+
+    if mismatched.is_empty() {
+        return Ok(());
+    }
+
+    mismatched.sort_unstable();
+    mismatched.dedup();
+    Err(PaymentError::VerificationError(format!(
+        "Replayed state does not match snapshot for clients: {:?}",
+        mismatched
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::retention::{Retention, RetentionPolicy};
+    use crate::engine::snapshot::save_snapshot;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_replay_matches_snapshot() {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("journal.csv");
+        let snapshot_path = dir.path().join("accounts.snapshot");
+
+        let account_service = AccountService::new();
+        account_service.set_journal(Journal::open(&journal_path).unwrap());
+        for line in [
+            "deposit,1,1,10.5",
+            "withdrawal,1,2,20.0",
+            "deposit,2,3,4.0",
+            "dispute,2,3,",
+            "chargeback,2,3,",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+        save_snapshot(&account_service, &snapshot_path).unwrap();
+
+        let replayed = AccountService::new();
+        replay(&journal_path, &replayed).unwrap();
+        verify(&replayed, &snapshot_path).unwrap();
+        assert!(replayed.get_account(2).unwrap().unwrap().locked());
+
+        // Anything applied outside of the journal is caught
+        replayed
+            .process_transaction(Transaction::from_str("deposit,1,4,1.0").unwrap())
+            .await
+            .unwrap();
+        assert!(verify(&replayed, &snapshot_path).is_err());
+    }
+
+    #[test]
+    fn test_replay_uses_recorded_time() {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("journal.csv");
+        // The dispute came after the dispute window closed, however long ago that was
+        std::fs::write(
+            &journal_path,
+            format!(
+                "{}\ndeposit,1,1,5.0,100\ndispute,1,1,,200\n",
+                JOURNAL_HEADER
+            ),
+        )
+        .unwrap();
+
+        let replayed = AccountService::new();
+        replayed.set_retention(Retention::new(RetentionPolicy {
+            dispute_window: Some(Duration::from_secs(50)),
+            ..Default::default()
+        }));
+        replay(&journal_path, &replayed).unwrap();
+
+        let account = replayed.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 5.0);
+        assert_eq!(account.held(), 0.0);
+    }
+}
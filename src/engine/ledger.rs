@@ -0,0 +1,262 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::Account;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Write};
+
+// Amounts are kept as integers in units of 1/100000000 so postings always net to exactly
+// zero. That is finer than the 4 decimal places balances are reported with, so amounts
+// below it still add up. Account balances are kept in the same units so they can be
+// compared with the ledger exactly.
+const UNITS_PER_WHOLE: f64 = 100_000_000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LedgerAccount {
+    Available(u16),
+    Held(u16),
+    // Counterpart of money entering or leaving the engine through deposits and withdrawals
+    Funding,
+    // Money taken back from clients by chargebacks
+    ChargebackLoss,
+    // Counterpart of the balances accounts already had when the ledger first saw them
+    OpeningBalance,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Available(client_id) => write!(f, "client:{}:available", client_id),
+            LedgerAccount::Held(client_id) => write!(f, "client:{}:held", client_id),
+            LedgerAccount::Funding => write!(f, "system:funding"),
+            LedgerAccount::ChargebackLoss => write!(f, "system:chargeback_loss"),
+            LedgerAccount::OpeningBalance => write!(f, "system:opening_balance"),
+        }
+    }
+}
+
+// A single balance change in ledger units, entries are always posted in balanced groups
+pub type Posting = (LedgerAccount, i64);
+
+#[derive(Clone, Debug, Default)]
+pub struct Ledger {
+    balances: BTreeMap<LedgerAccount, i64>,
+    entries: u64,
+}
+
+impl Ledger {
+    pub fn new() -> Self {
+        Self {
+            balances: BTreeMap::new(),
+            entries: 0,
+        }
+    }
+
+    // Rejects the whole group unless it nets to zero
+    pub fn post(&mut self, postings: &[Posting]) -> Result<(), PaymentError> {
+        let net: i64 = postings.iter().map(|(_, amount)| amount).sum();
+        if net != 0 {
+            return Err(PaymentError::PolicyError(format!(
+                "Unbalanced ledger entries: {:?}",
+                postings
+            )));
+        }
+
+        for (account, amount) in postings {
+            *self.balances.entry(*account).or_insert(0) += amount;
+        }
+        self.entries += postings.len() as u64;
+        Ok(())
+    }
+
+    // Posts the balances an account already has against the opening balance account the
+    // first time the ledger sees it, e.g. accounts restored from a snapshot or already in a
+    // store. Afterwards the ledger follows every change to the account.
+    pub fn open_account(&mut self, client_id: u16, available: i64, held: i64) {
+        if self
+            .balances
+            .contains_key(&LedgerAccount::Available(client_id))
+        {
+            return;
+        }
+        self.balances
+            .insert(LedgerAccount::Available(client_id), available);
+        self.balances.insert(LedgerAccount::Held(client_id), held);
+        *self
+            .balances
+            .entry(LedgerAccount::OpeningBalance)
+            .or_insert(0) -= available + held;
+        self.entries += 3;
+    }
+
+    // Clients with accounts in the ledger
+    pub fn client_ids(&self) -> BTreeSet<u16> {
+        self.balances
+            .keys()
+            .filter_map(|account| match account {
+                LedgerAccount::Available(client_id) | LedgerAccount::Held(client_id) => {
+                    Some(*client_id)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> f64 {
+        from_units(self.units(account))
+    }
+
+    fn units(&self, account: LedgerAccount) -> i64 {
+        self.balances.get(&account).cloned().unwrap_or(0)
+    }
+
+    pub fn trial_balance(&self) -> TrialBalance {
+        TrialBalance {
+            balances: self
+                .balances
+                .iter()
+                .map(|(account, amount)| (*account, from_units(*amount)))
+                .collect(),
+            total: from_units(self.balances.values().sum()),
+            entries: self.entries,
+            mismatches: Vec::new(),
+        }
+    }
+}
+
+// A client ledger account that disagrees with the balance of the account it follows
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LedgerMismatch {
+    pub account: LedgerAccount,
+    pub ledger: f64,
+    pub balance: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrialBalance {
+    pub balances: Vec<(LedgerAccount, f64)>,
+    // Must be zero, otherwise money was created or destroyed
+    pub total: f64,
+    pub entries: u64,
+    // Must be empty, otherwise a balance changed without being posted to the ledger
+    pub mismatches: Vec<LedgerMismatch>,
+}
+
+impl TrialBalance {
+    // Nets to zero and every client ledger account agrees with the account it follows
+    pub fn is_balanced(&self) -> bool {
+        self.total == 0.0 && self.mismatches.is_empty()
+    }
+
+    // Checks the client ledger accounts of `account` against its balances
+    pub(crate) fn reconcile(&mut self, ledger: &Ledger, account: &Account) {
+        for (ledger_account, balance) in [
+            (
+                LedgerAccount::Available(account.client_id),
+                account.available,
+            ),
+            (LedgerAccount::Held(account.client_id), account.held),
+        ] {
+            if ledger.units(ledger_account) != balance {
+                self.mismatches.push(LedgerMismatch {
+                    account: ledger_account,
+                    ledger: ledger.balance(ledger_account),
+                    balance: from_units(balance),
+                });
+            }
+        }
+    }
+
+    // Mismatched accounts are listed after the total with the balance of the account
+
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "account,balance").map_err(write_error)?;
+        for (account, balance) in &self.balances {
+            writeln!(writer, "{},{}", account, balance).map_err(write_error)?;
+        }
+        writeln!(writer, "total,{}", self.total).map_err(write_error)?;
+        for mismatch in &self.mismatches {
+            writeln!(writer, "mismatch:{},{}", mismatch.account, mismatch.balance)
+                .map_err(write_error)?;
+        }
+        Ok(())
+    }
+}
+
+// Amounts are rounded to the nearest unit. Done on the shortest representation of the
+// amount, widening the float would turn e.g. 0.1 into 0.100000001.
+pub(crate) fn to_units(amount: f32) -> i64 {
+    let amount: f64 = amount.to_string().parse().unwrap_or(amount as f64);
+    decimal_to_units(amount)
+}
+
+pub(crate) fn decimal_to_units(amount: f64) -> i64 {
+    (amount * UNITS_PER_WHOLE).round() as i64
+}
+
+pub(crate) fn from_units(units: i64) -> f64 {
+    units as f64 / UNITS_PER_WHOLE
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the trial balance")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trial_balance() {
+        let mut ledger = Ledger::new();
+        ledger
+            .post(&[
+                (LedgerAccount::Available(1), to_units(10.12345)),
+                (LedgerAccount::Funding, -to_units(10.12345)),
+            ])
+            .unwrap();
+        ledger
+            .post(&[
+                (LedgerAccount::Available(1), -to_units(2.5)),
+                (LedgerAccount::Held(1), to_units(2.5)),
+            ])
+            .unwrap();
+        assert!(ledger
+            .post(&[
+                (LedgerAccount::Available(1), to_units(1.0)),
+                (LedgerAccount::Funding, -to_units(0.5)),
+            ])
+            .is_err());
+
+        let trial_balance = ledger.trial_balance();
+        assert!(trial_balance.is_balanced());
+        assert_eq!(trial_balance.entries, 4);
+        assert_eq!(ledger.balance(LedgerAccount::Available(1)), 7.62345);
+        assert_eq!(ledger.balance(LedgerAccount::Held(1)), 2.5);
+
+        let mut csv = Vec::new();
+        trial_balance.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "account,balance\n\
+             client:1:available,7.62345\n\
+             client:1:held,2.5\n\
+             system:funding,-10.12345\n\
+             total,0\n"
+        );
+
+        // Accounts the ledger has already seen aren't opened again
+        ledger.open_account(2, to_units(3.0), to_units(1.0));
+        ledger.open_account(2, to_units(5.0), 0);
+        assert_eq!(ledger.balance(LedgerAccount::Available(2)), 3.0);
+        assert_eq!(ledger.balance(LedgerAccount::OpeningBalance), -4.0);
+        assert_eq!(
+            ledger.client_ids().into_iter().collect::<Vec<u16>>(),
+            vec![1, 2]
+        );
+        assert!(ledger.trial_balance().is_balanced());
+    }
+}
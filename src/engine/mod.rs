@@ -1,4 +1,25 @@
+pub mod audit;
+#[cfg(feature = "runtime")]
+pub mod builder;
+pub mod checkpoint;
+pub mod config;
+pub mod diff;
 pub mod download;
+pub mod dry_run;
 pub mod errors;
+#[cfg(feature = "runtime")]
 pub mod ingestion;
+pub mod journal;
+pub mod ledger;
+pub mod observer;
 pub mod payments;
+pub mod policy;
+pub mod query;
+pub mod reconcile;
+pub mod report;
+pub mod retention;
+pub mod snapshot;
+pub mod statement;
+pub mod stats;
+pub mod store;
+pub mod upload;
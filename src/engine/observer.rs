@@ -0,0 +1,152 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::{Account, Transaction, TransactionOutcome, TransactionType};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Balances {
+    pub available: f32,
+    pub held: f32,
+    pub locked: bool,
+}
+
+impl Balances {
+    pub fn of(account: &Account) -> Self {
+        Self {
+            available: account.available(),
+            held: account.held(),
+            locked: account.locked(),
+        }
+    }
+}
+
+// What one processed transaction did to its account. Rejected transactions leave the
+// balances untouched so `before` and `after` are the same. A transfer, and a dispute, resolve
+// or chargeback of one, changes two accounts and is reported once for each of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountEvent<'a> {
+    // The account the balances belong to
+    pub client_id: u16,
+    pub transaction: &'a Transaction,
+    pub before: Balances,
+    pub after: Balances,
+    pub outcome: TransactionOutcome,
+}
+
+impl AccountEvent<'_> {
+    pub fn applied(&self) -> bool {
+        self.outcome == TransactionOutcome::Applied
+    }
+
+    pub fn became_locked(&self) -> bool {
+        !self.before.locked && self.after.locked
+    }
+
+    pub fn dispute_opened(&self) -> bool {
+        self.applied() && self.transaction.transaction_type() == TransactionType::Dispute
+    }
+}
+
+// Called by the account service after every applied or rejected transaction, in the order
+// they were processed. The account stays locked while observers run so they should be quick.
+// By then the transaction is already stored and an error doesn't undo it: every observer is
+// still told about every account it changed, then the first error is returned and stops
+// processing.
+pub trait AccountObserver: Send {
+    fn on_transaction(&mut self, event: &AccountEvent) -> Result<(), PaymentError>;
+
+    // Length of what has been written so far, recorded by checkpoints so a resumed run can
+    // drop what was written for the rows it applies again. None if it can't be rewound.
+    fn position(&mut self) -> Result<Option<u64>, PaymentError> {
+        Ok(None)
+    }
+
+    // Drops everything written after `position`
+    fn truncate(&mut self, _position: u64) -> Result<(), PaymentError> {
+        Ok(())
+    }
+}
+
+// Lets a closure be registered directly, e.g. to alert on large withdrawals
+impl<F> AccountObserver for F
+where
+    F: FnMut(&AccountEvent) -> Result<(), PaymentError> + Send,
+{
+    fn on_transaction(&mut self, event: &AccountEvent) -> Result<(), PaymentError> {
+        self(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::payments::AccountService;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_observer() {
+        let locked = Arc::new(Mutex::new(Vec::new()));
+        let disputes = Arc::new(Mutex::new(0));
+        let account_service = AccountService::new();
+        {
+            let locked = locked.clone();
+            let disputes = disputes.clone();
+            account_service.add_observer(Box::new(move |event: &AccountEvent| {
+                if event.became_locked() {
+                    locked.lock().unwrap().push(event.client_id);
+                }
+                if event.dispute_opened() {
+                    *disputes.lock().unwrap() += 1;
+                }
+                Ok(())
+            }));
+        }
+
+        for transaction in [
+            Transaction::deposit(1, 1, 10.0).unwrap(),
+            Transaction::deposit(2, 2, 5.0).unwrap(),
+            Transaction::dispute(1, 1),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+            Transaction::dispute(2, 2),
+        ] {
+            account_service
+                .process_transaction(transaction)
+                .await
+                .unwrap();
+        }
+        assert_eq!(*locked.lock().unwrap(), vec![1]);
+        assert_eq!(*disputes.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failing_observer() {
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let account_service = AccountService::new();
+        account_service.add_observer(Box::new(|_: &AccountEvent| {
+            Err(PaymentError::PolicyError("Observer failed".to_string()))
+        }));
+        {
+            let notified = notified.clone();
+            account_service.add_observer(Box::new(move |event: &AccountEvent| {
+                notified.lock().unwrap().push(event.client_id);
+                Ok(())
+            }));
+        }
+
+        assert!(account_service
+            .process_transaction(Transaction::deposit(1, 1, 10.0).unwrap())
+            .await
+            .is_err());
+        assert!(account_service
+            .process_transaction(Transaction::transfer(1, 2, 2, 4.0).unwrap())
+            .await
+            .is_err());
+        // The later observer was still notified of both accounts and the transactions are
+        // applied regardless
+        assert_eq!(*notified.lock().unwrap(), vec![1, 1, 2]);
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().available(),
+            4.0
+        );
+    }
+}
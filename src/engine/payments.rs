@@ -1,41 +1,78 @@
 use crate::engine::errors::PaymentError;
+#[cfg(feature = "runtime")]
 use crate::engine::ingestion::PaymentsQueue;
+use crate::engine::journal::Journal;
+use crate::engine::ledger::{self, Ledger, LedgerAccount, Posting, TrialBalance};
+use crate::engine::observer::{AccountEvent, AccountObserver, Balances};
+use crate::engine::policy::Policy;
+use crate::engine::query::{AccountPage, AccountQuery, AccountTotals, Accounts};
+use crate::engine::report::{OutputFormat, ReportOptions, SortOrder};
+use crate::engine::retention::{self, Retention};
+use crate::engine::stats::RunStats;
+use crate::engine::store::{AccountStore, InMemoryStore};
 use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
+// Number of accounts read from the store at a time when going through all of them
+const ACCOUNTS_PAGE_SIZE: usize = 1000;
+
+// Worker of the async ingestion layer, applies queued rows with `AccountService::process_row`
+#[cfg(feature = "runtime")]
 pub struct PaymentsProcessor {
     payments_queue: PaymentsQueue,
     account_service: AccountService,
+    // Partition of the queue this processor takes rows from
+    partition: usize,
 }
 
+#[cfg(feature = "runtime")]
 impl PaymentsProcessor {
     pub fn new(payments_queue: PaymentsQueue, account_service: AccountService) -> Self {
         Self {
             payments_queue,
             account_service,
+            partition: 0,
         }
     }
 
+    pub fn with_partition(mut self, partition: usize) -> Self {
+        self.partition = partition;
+        self
+    }
+
+    // Applies queued rows until the queue is closed. A row that fails closes the queue so
+    // the other workers stop too, the error is returned by `shutdown_gracefully`.
     pub async fn start(&self) -> Result<(), PaymentError> {
-        while let Some(transaction_string) = self.payments_queue.get_transaction() {
-            let transaction = Transaction::from_str(&transaction_string)?;
-            self.account_service
-                .process_transaction(transaction)
-                .await?;
+        while let Some(transaction_string) =
+            self.payments_queue.take_transaction(self.partition).await
+        {
+            let processed = self.account_service.process_row(&transaction_string);
+            self.payments_queue.done();
+            if processed.is_err() {
+                self.payments_queue.close();
+            }
+            processed?;
         }
 
         Ok(())
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-enum TransactionType {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    // Moves funds from the client's account to another client's account
+    Transfer,
 }
 
 impl FromStr for TransactionType {
@@ -48,21 +85,64 @@ impl FromStr for TransactionType {
             "dispute" => Ok(TransactionType::Dispute),
             "resolve" => Ok(TransactionType::Resolve),
             "chargeback" => Ok(TransactionType::Chargeback),
-            _ => Err(Self::Err::PaymentProcessingError(format!(
+            "transfer" => Ok(TransactionType::Transfer),
+            _ => Err(Self::Err::ParseError(format!(
                 "Invalid transaction type: {}",
-                s.to_string()
+                s
             ))),
         }
     }
 }
 
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Transfer => "transfer",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Transaction {
-    transaction_type: TransactionType,
-    client_id: u16,
-    transaction_id: u32,
-    amount: f32,
-    under_dispute: bool,
+    pub(crate) transaction_type: TransactionType,
+    pub(crate) client_id: u16,
+    pub(crate) transaction_id: u32,
+    pub(crate) amount: f32,
+    // Client receiving the funds of a transfer, None for every other type
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub(crate) destination_id: Option<u16>,
+    pub(crate) under_dispute: bool,
+    // A chargeback is final, the transaction can't be disputed again
+    pub(crate) charged_back: bool,
+    // When the transaction was stored for disputes, used by the retention policy
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) sequence: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) recorded_at: u64,
+}
+
+// The retention bookkeeping is left out so accounts holding the same transactions compare
+// equal no matter when they were processed
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Self) -> bool {
+        self.transaction_type == other.transaction_type
+            && self.client_id == other.client_id
+            && self.transaction_id == other.transaction_id
+            && self.amount == other.amount
+            && self.destination_id == other.destination_id
+            && self.under_dispute == other.under_dispute
+            && self.charged_back == other.charged_back
+    }
 }
 
 impl FromStr for Transaction {
@@ -71,80 +151,338 @@ impl FromStr for Transaction {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = s.split(",").collect();
         if parts.len() < 4 {
-            return Err(PaymentError::PaymentProcessingError(
-                "Invalid input data".to_string(),
-            ));
+            return Err(PaymentError::ParseError("Invalid input data".to_string()));
         }
 
         let transaction_type = TransactionType::from_str(parts[0].trim())?;
-        let client_id = parts[1].trim().parse::<u16>().map_err(|_| {
-            PaymentError::PaymentProcessingError("Could not parse client id".to_string())
-        })?;
-        let transaction_id = parts[2].trim().parse::<u32>().map_err(|_| {
-            PaymentError::PaymentProcessingError("Could not parse transaction id".to_string())
-        })?;
+        let client_id = parts[1]
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| PaymentError::ParseError("Could not parse client id".to_string()))?;
+        let transaction_id = parts[2]
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| PaymentError::ParseError("Could not parse transaction id".to_string()))?;
 
         let mut amount = 0.0;
-        if transaction_type == TransactionType::Deposit
-            || transaction_type == TransactionType::Withdrawal
-        {
-            amount = parts[3].trim().parse::<f32>().map_err(|_| {
-                PaymentError::PaymentProcessingError("Could not parse amount".to_string())
-            })?;
+        if transaction_type.has_amount() {
+            amount = parts[3]
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| PaymentError::ParseError("Could not parse amount".to_string()))?;
+        }
+
+        let mut transaction = Self::parsed(transaction_type, client_id, transaction_id, amount);
+        // Transfers have the destination client as an extra column
+        if transaction_type == TransactionType::Transfer {
+            transaction.destination_id = Some(
+                parts
+                    .get(4)
+                    .and_then(|part| part.trim().parse::<u16>().ok())
+                    .ok_or_else(|| {
+                        PaymentError::ParseError(
+                            "Could not parse destination client id".to_string(),
+                        )
+                    })?,
+            );
         }
+        Ok(transaction)
+    }
+}
+
+impl TransactionType {
+    // Disputes, resolves and chargebacks refer to the amount of the disputed transaction
+    pub fn has_amount(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer
+        )
+    }
+}
 
-        Ok(Self {
+impl Transaction {
+    // Deposits, withdrawals and transfers need a positive, finite amount, it is ignored
+    // for every other type. Transfers also need a destination, see `transfer`.
+    pub fn new(
+        transaction_type: TransactionType,
+        client_id: u16,
+        transaction_id: u32,
+        amount: f32,
+    ) -> Result<Self, PaymentError> {
+        if transaction_type == TransactionType::Transfer {
+            return Err(PaymentError::ParseError(
+                "A transfer needs a destination client".to_string(),
+            ));
+        }
+        if transaction_type.has_amount() {
+            check_amount(amount)?;
+        }
+        Ok(Self::parsed(
             transaction_type,
             client_id,
             transaction_id,
             amount,
+        ))
+    }
+
+    // Doesn't check the amount, a parsed row with an invalid amount is rejected when it is
+    // processed rather than stopping the run
+    fn parsed(
+        transaction_type: TransactionType,
+        client_id: u16,
+        transaction_id: u32,
+        amount: f32,
+    ) -> Self {
+        Self {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount: if transaction_type.has_amount() {
+                amount
+            } else {
+                0.0
+            },
+            destination_id: None,
             under_dispute: false,
-        })
+            charged_back: false,
+            sequence: 0,
+            recorded_at: 0,
+        }
+    }
+
+    pub fn deposit(client_id: u16, transaction_id: u32, amount: f32) -> Result<Self, PaymentError> {
+        Self::new(TransactionType::Deposit, client_id, transaction_id, amount)
+    }
+
+    pub fn withdrawal(
+        client_id: u16,
+        transaction_id: u32,
+        amount: f32,
+    ) -> Result<Self, PaymentError> {
+        Self::new(
+            TransactionType::Withdrawal,
+            client_id,
+            transaction_id,
+            amount,
+        )
+    }
+
+    // Moves `amount` from `client_id` to `destination_id` as a single transaction. It is
+    // stored with the source client, who disputes it like any other transaction.
+    pub fn transfer(
+        client_id: u16,
+        destination_id: u16,
+        transaction_id: u32,
+        amount: f32,
+    ) -> Result<Self, PaymentError> {
+        if destination_id == client_id {
+            return Err(PaymentError::ParseError(
+                "A transfer needs a destination client other than the source".to_string(),
+            ));
+        }
+        check_amount(amount)?;
+        let mut transaction =
+            Self::parsed(TransactionType::Transfer, client_id, transaction_id, amount);
+        transaction.destination_id = Some(destination_id);
+        Ok(transaction)
+    }
+
+    // `transaction_id` is the deposit, withdrawal or transfer being disputed
+    pub fn dispute(client_id: u16, transaction_id: u32) -> Self {
+        Self::without_amount(TransactionType::Dispute, client_id, transaction_id)
+    }
+
+    pub fn resolve(client_id: u16, transaction_id: u32) -> Self {
+        Self::without_amount(TransactionType::Resolve, client_id, transaction_id)
+    }
+
+    pub fn chargeback(client_id: u16, transaction_id: u32) -> Self {
+        Self::without_amount(TransactionType::Chargeback, client_id, transaction_id)
+    }
+
+    fn without_amount(
+        transaction_type: TransactionType,
+        client_id: u16,
+        transaction_id: u32,
+    ) -> Self {
+        Self::parsed(transaction_type, client_id, transaction_id, 0.0)
+    }
+
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    pub fn client_id(&self) -> u16 {
+        self.client_id
+    }
+
+    pub fn transaction_id(&self) -> u32 {
+        self.transaction_id
+    }
+
+    // None for the types that refer to another transaction's amount
+    pub fn amount(&self) -> Option<f32> {
+        self.transaction_type.has_amount().then_some(self.amount)
+    }
+
+    // None for everything but transfers
+    pub fn destination_id(&self) -> Option<u16> {
+        self.destination_id
+    }
+
+    pub fn under_dispute(&self) -> bool {
+        self.under_dispute
+    }
+
+    pub fn charged_back(&self) -> bool {
+        self.charged_back
     }
 }
 
-#[derive(Clone, Debug)]
+fn check_amount(amount: f32) -> Result<(), PaymentError> {
+    if !amount.is_finite() || amount <= 0.0 {
+        return Err(PaymentError::ParseError(format!(
+            "Invalid amount: {}",
+            amount
+        )));
+    }
+    Ok(())
+}
+
+// Written in the same format as the input csv so it can be parsed back with `from_str`
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => write!(
+                f,
+                "{},{},{},{}",
+                self.transaction_type, self.client_id, self.transaction_id, self.amount
+            ),
+            TransactionType::Transfer => write!(
+                f,
+                "{},{},{},{},{}",
+                self.transaction_type,
+                self.client_id,
+                self.transaction_id,
+                self.amount,
+                self.destination_id.unwrap_or_default()
+            ),
+            _ => write!(
+                f,
+                "{},{},{},",
+                self.transaction_type, self.client_id, self.transaction_id
+            ),
+        }
+    }
+}
+
+// What `process_transaction` did with a transaction. Rejected transactions leave the
+// balances untouched, they are not errors since the input is expected to contain them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum TransactionOutcome {
+    Applied,
+    Rejected(RejectionReason),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum RejectionReason {
+    AccountLocked,
+    InsufficientFunds,
+    // The referenced transaction doesn't exist, belongs to another client or was evicted
+    UnknownTransaction,
+    AlreadyDisputed,
+    NotDisputed,
+    // Disputing a withdrawal when the policy doesn't allow it
+    DisputeNotAllowed,
+    // The referenced transaction was already charged back
+    ChargedBack,
+    // A zero, negative or non-finite amount, after rounding
+    InvalidAmount,
+    // A transfer to the client's own account
+    InvalidDestination,
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RejectionReason::AccountLocked => "account_locked",
+            RejectionReason::InsufficientFunds => "insufficient_funds",
+            RejectionReason::UnknownTransaction => "unknown_transaction",
+            RejectionReason::AlreadyDisputed => "already_disputed",
+            RejectionReason::NotDisputed => "not_disputed",
+            RejectionReason::DisputeNotAllowed => "dispute_not_allowed",
+            RejectionReason::ChargedBack => "charged_back",
+            RejectionReason::InvalidAmount => "invalid_amount",
+            RejectionReason::InvalidDestination => "invalid_destination",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Account {
-    client_id: u16,
-    available: f32,
-    held: f32,
-    locked: bool,
+    pub(crate) client_id: u16,
+    // Balances are in ledger units so they never accumulate rounding errors
+    pub(crate) available: i64,
+    pub(crate) held: i64,
+    pub(crate) locked: bool,
     // Map of transaction id to transaction
-    transactions: HashMap<u32, Transaction>,
+    pub(crate) transactions: HashMap<u32, Transaction>,
 }
 
 impl Account {
     pub fn new(client_id: &u16) -> Self {
         Self {
             client_id: *client_id,
-            available: 0.0,
-            held: 0.0,
+            available: 0,
+            held: 0,
             locked: false,
             transactions: HashMap::new(),
         }
     }
 
-    fn truncate(&self, num: &f32) -> f32 {
-        let s = format!("{:.4}", num);
-        s.parse::<f32>().expect("truncate failed")
+    pub fn client_id(&self) -> u16 {
+        self.client_id
     }
 
     pub fn total(&self) -> f32 {
-        let t = self.held + self.available;
-        self.truncate(&t)
+        rounded(self.held + self.available)
     }
 
     pub fn available(&self) -> f32 {
-        self.truncate(&self.available)
+        rounded(self.available)
     }
 
     pub fn held(&self) -> f32 {
-        self.truncate(&self.held)
+        rounded(self.held)
     }
 
     pub fn locked(&self) -> bool {
         self.locked
     }
+
+    // Copy of the account without its transactions
+    pub(crate) fn balances(&self) -> Self {
+        Self {
+            client_id: self.client_id,
+            available: self.available,
+            held: self.held,
+            locked: self.locked,
+            transactions: HashMap::new(),
+        }
+    }
+}
+
+// Balances are reported with 4 decimal places, rounded
+fn rounded(units: i64) -> f32 {
+    format!("{:.4}", ledger::from_units(units))
+        .parse()
+        .expect("Formatted balances always parse")
 }
 
 #[derive(Clone)]
@@ -158,134 +496,962 @@ pub struct AccountService {
     // previously initialized a Mutex around each account would be sufficient to allow concurrent
     // processing on distinct accounts. I could fix this with more work but don't have time at the moment.
 
-    // Map of client id to account
-    accounts: Arc<Mutex<HashMap<u16, Account>>>,
+    // Where the accounts live, in memory unless a different store is provided
+    accounts: Arc<Mutex<Box<dyn AccountStore>>>,
+    // Shared between clones so attaching a journal affects every handle to this service
+    journal: Arc<Mutex<Option<Journal>>>,
+    retention: Arc<Mutex<Retention>>,
+    // Order in which transactions were stored, used by the retention policy
+    sequence: Arc<AtomicU64>,
+    // Every balance change is also posted here as balanced entries to prove money is conserved
+    ledger: Arc<Mutex<Ledger>>,
+    stats: Arc<Mutex<RunStats>>,
+    observers: Arc<Mutex<Vec<Box<dyn AccountObserver>>>>,
+    policy: Arc<Mutex<Policy>>,
+}
+
+impl Default for AccountService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AccountService {
     pub fn new() -> Self {
+        Self::with_store(Box::new(InMemoryStore::new()))
+    }
+
+    pub fn with_store(store: Box<dyn AccountStore>) -> Self {
         Self {
-            accounts: Arc::new(Mutex::new(HashMap::new())),
+            accounts: Arc::new(Mutex::new(store)),
+            journal: Arc::new(Mutex::new(None)),
+            retention: Arc::new(Mutex::new(Retention::default())),
+            sequence: Arc::new(AtomicU64::new(0)),
+            ledger: Arc::new(Mutex::new(Ledger::new())),
+            stats: Arc::new(Mutex::new(RunStats::new())),
+            observers: Arc::new(Mutex::new(Vec::new())),
+            policy: Arc::new(Mutex::new(Policy::default())),
         }
     }
 
-    pub async fn process_transaction(&self, transaction: Transaction) -> Result<(), PaymentError> {
-        let mut accounts = self.accounts.lock().expect("Ignore lock poisoning");
-        let mut account = accounts
-            .get(&transaction.client_id)
-            .cloned()
-            .unwrap_or(Account::new(&transaction.client_id));
+    // Every transaction is appended to the journal before any state is mutated
+    pub fn set_journal(&self, journal: Journal) {
+        *self.journal.lock().expect("Ignore lock poisoning") = Some(journal);
+    }
+
+    // None if no journal is attached
+    pub fn journal_position(&self) -> Result<Option<u64>, PaymentError> {
+        self.journal
+            .lock()
+            .expect("Ignore lock poisoning")
+            .as_ref()
+            .map(Journal::position)
+            .transpose()
+    }
 
-        if account.locked() {
-            return Ok(());
+    pub fn truncate_journal(&self, position: u64) -> Result<(), PaymentError> {
+        match self.journal.lock().expect("Ignore lock poisoning").as_mut() {
+            Some(journal) => journal.truncate(position),
+            None => Ok(()),
         }
+    }
 
+    // Every observer is told about each processed transaction, in processing order
+    pub fn add_observer(&self, observer: Box<dyn AccountObserver>) {
+        self.observers
+            .lock()
+            .expect("Ignore lock poisoning")
+            .push(observer);
+    }
+
+    // Positions of the observers that can be rewound, in the order they were added
+    pub fn observer_positions(&self) -> Result<Vec<u64>, PaymentError> {
+        let mut positions = Vec::new();
+        for observer in self
+            .observers
+            .lock()
+            .expect("Ignore lock poisoning")
+            .iter_mut()
+        {
+            if let Some(position) = observer.position()? {
+                positions.push(position);
+            }
+        }
+        Ok(positions)
+    }
+
+    // Rewinds the observers that can be rewound to the positions from `observer_positions`
+    pub fn truncate_observers(&self, positions: &[u64]) -> Result<(), PaymentError> {
+        let mut positions = positions.iter();
+        for observer in self
+            .observers
+            .lock()
+            .expect("Ignore lock poisoning")
+            .iter_mut()
+        {
+            if observer.position()?.is_some() {
+                if let Some(position) = positions.next() {
+                    observer.truncate(*position)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_policy(&self, policy: Policy) {
+        *self.policy.lock().expect("Ignore lock poisoning") = policy;
+    }
+
+    pub fn policy(&self) -> Policy {
+        *self.policy.lock().expect("Ignore lock poisoning")
+    }
+
+    // Stored transactions are kept forever unless a retention policy is set
+    pub fn set_retention(&self, retention: Retention) {
+        *self.retention.lock().expect("Ignore lock poisoning") = retention;
+    }
+
+    // Same as `apply`, for callers that are already async
+    pub async fn process_transaction(
+        &self,
+        transaction: Transaction,
+    ) -> Result<TransactionOutcome, PaymentError> {
+        self.apply(transaction)
+    }
+
+    // Parses one csv row and applies it, a row that can't be parsed is counted as a
+    // parse failure in the run stats
+    pub fn process_row(&self, row: &str) -> Result<TransactionOutcome, PaymentError> {
+        let transaction = Transaction::from_str(row).inspect_err(|_| {
+            self.record_parse_failure();
+        })?;
+        self.apply(transaction)
+    }
+
+    // Processes a whole csv input on the calling thread, stopping at the first row that
+    // can't be parsed like the workers do. The first line is the header.
+    pub fn process_csv<R: BufRead>(&self, reader: R) -> Result<(), PaymentError> {
+        for row in reader.lines().skip(1) {
+            let row = row.map_err(PaymentError::source_error("Could not read the input"))?;
+            self.record_rows_read(1);
+            self.process_row(&row)?;
+        }
+        Ok(())
+    }
+
+    // The synchronous core of the engine, everything else ends up here. Needs no runtime,
+    // the accounts are locked for the duration of the call.
+    pub fn apply(&self, transaction: Transaction) -> Result<TransactionOutcome, PaymentError> {
+        self.apply_at(transaction, retention::now())
+    }
+
+    // Applies the transaction as if it happened at `now`, in seconds since the epoch. The
+    // time is journaled along with the transaction so a replay applies the retention policy
+    // the same way the original run did.
+    pub fn apply_at(
+        &self,
+        mut transaction: Transaction,
+        now: u64,
+    ) -> Result<TransactionOutcome, PaymentError> {
+        let policy = self.policy();
+        if transaction.transaction_type.has_amount() {
+            transaction.amount = policy.rounding.apply(transaction.amount);
+        }
+        // Only a deserialized transaction can get here without a destination, or with one it
+        // shouldn't have. Refused before it is journaled so a replay never sees it.
         match transaction.transaction_type {
+            TransactionType::Transfer if transaction.destination_id.is_none() => {
+                return Err(PaymentError::ParseError(
+                    "A transfer needs a destination client".to_string(),
+                ));
+            }
+            TransactionType::Transfer => {}
+            _ => transaction.destination_id = None,
+        }
+
+        let mut accounts = self.accounts.lock().expect("Ignore lock poisoning");
+        // Appended while holding the accounts lock so the journal order matches the order
+        // transactions are applied in
+        if let Some(journal) = self.journal.lock().expect("Ignore lock poisoning").as_mut() {
+            journal.append(&transaction, now)?;
+        }
+
+        let mut account = self.open_account(accounts.as_mut(), transaction.client_id)?;
+        let before = Balances::of(&account);
+
+        if account.locked()
+            && (policy.locked_accounts_reject_all
+                || matches!(
+                    transaction.transaction_type,
+                    TransactionType::Withdrawal | TransactionType::Transfer
+                ))
+        {
+            let outcome = TransactionOutcome::Rejected(RejectionReason::AccountLocked);
+            self.stats.lock().expect("Ignore lock poisoning").record(
+                &transaction.transaction_type,
+                &outcome,
+                &[],
+            );
+            self.notify(&transaction, transaction.client_id, before, before, outcome)?;
+            return Ok(outcome);
+        }
+
+        let retention = self.retention.lock().expect("Ignore lock poisoning");
+        transaction.sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        transaction.recorded_at = now;
+        // Stored transactions start out undisputed, whatever a deserialized one says
+        transaction.under_dispute = false;
+        transaction.charged_back = false;
+
+        let client_id = transaction.client_id;
+        // The other account of a transfer, or of a dispute, resolve or chargeback of one,
+        // with its balances before the transaction
+        let mut counterparty: Option<(Account, Balances)> = None;
+        let applied: Result<Vec<Posting>, RejectionReason> = match transaction.transaction_type {
+            // Checked after rounding, which can bring a small amount down to zero
+            transaction_type
+                if transaction_type.has_amount()
+                    && (!transaction.amount.is_finite()
+                        || ledger::to_units(transaction.amount) <= 0) =>
+            {
+                Err(RejectionReason::InvalidAmount)
+            }
             TransactionType::Deposit => {
-                account.available += transaction.amount;
+                let amount = ledger::to_units(transaction.amount);
+                account.available += amount;
                 account
                     .transactions
                     .insert(transaction.transaction_id, transaction.clone());
+                Ok(vec![
+                    (LedgerAccount::Available(client_id), amount),
+                    (LedgerAccount::Funding, -amount),
+                ])
             }
             TransactionType::Withdrawal => {
-                if account.available >= transaction.amount {
-                    account.available -= transaction.amount;
+                let amount = ledger::to_units(transaction.amount);
+                if account.available >= amount {
+                    account.available -= amount;
 
                     account
                         .transactions
                         .insert(transaction.transaction_id, transaction.clone());
+                    Ok(vec![
+                        (LedgerAccount::Available(client_id), -amount),
+                        (LedgerAccount::Funding, amount),
+                    ])
+                } else {
+                    Err(RejectionReason::InsufficientFunds)
                 }
             }
-            TransactionType::Dispute => {
-                if let Some(mut disputed_transaction) = account
-                    .transactions
-                    .get(&transaction.transaction_id)
-                    .cloned()
-                {
-                    // Can only dispute a transaction that isn't already under dispute
-                    if !disputed_transaction.under_dispute {
-                        if disputed_transaction.transaction_type == TransactionType::Withdrawal {
-                            account.held += disputed_transaction.amount;
-                            disputed_transaction.under_dispute = true;
+            TransactionType::Transfer => match transaction.destination_id {
+                Some(destination_id) if destination_id != client_id => {
+                    let amount = ledger::to_units(transaction.amount);
+                    if account.available < amount {
+                        Err(RejectionReason::InsufficientFunds)
+                    } else {
+                        let mut destination =
+                            self.open_account(accounts.as_mut(), destination_id)?;
+                        if destination.locked() && policy.locked_accounts_reject_all {
+                            Err(RejectionReason::AccountLocked)
+                        } else {
+                            let destination_before = Balances::of(&destination);
+                            account.available -= amount;
+                            destination.available += amount;
+                            // Stored with the source client, who is the one that can dispute it
                             account
                                 .transactions
-                                .insert(disputed_transaction.transaction_id, disputed_transaction);
-                        } else if disputed_transaction.transaction_type == TransactionType::Deposit
-                        {
-                            account.available -= disputed_transaction.amount;
-                            account.held += disputed_transaction.amount;
-                            disputed_transaction.under_dispute = true;
-                            account
-                                .transactions
-                                .insert(disputed_transaction.transaction_id, disputed_transaction);
+                                .insert(transaction.transaction_id, transaction.clone());
+                            counterparty = Some((destination, destination_before));
+                            Ok(vec![
+                                (LedgerAccount::Available(client_id), -amount),
+                                (LedgerAccount::Available(destination_id), amount),
+                            ])
                         }
                     }
                 }
+                // The destination is the source itself
+                _ => Err(RejectionReason::InvalidDestination),
+            },
+            TransactionType::Dispute => {
+                // Evicted transactions can still be disputed if they were spilled to disk
+                let stored_transaction = match accounts
+                    .get_transaction(transaction.client_id, transaction.transaction_id)?
+                    .filter(|stored_transaction| retention.disputable(stored_transaction, now))
+                {
+                    Some(stored_transaction) => Some(stored_transaction),
+                    None => retention.find_spilled(
+                        transaction.client_id,
+                        transaction.transaction_id,
+                        now,
+                    )?,
+                };
+                match stored_transaction {
+                    // Can only dispute a transaction that isn't already under dispute
+                    Some(disputed_transaction) if disputed_transaction.under_dispute => {
+                        Err(RejectionReason::AlreadyDisputed)
+                    }
+                    Some(disputed_transaction) if disputed_transaction.charged_back => {
+                        Err(RejectionReason::ChargedBack)
+                    }
+                    Some(disputed_transaction)
+                        if disputed_transaction.transaction_type == TransactionType::Withdrawal
+                            && !policy.allow_withdrawal_disputes =>
+                    {
+                        Err(RejectionReason::DisputeNotAllowed)
+                    }
+                    Some(mut disputed_transaction) => {
+                        let amount = ledger::to_units(disputed_transaction.amount);
+                        let postings =
+                            if let Some(destination_id) = disputed_transaction.destination_id {
+                                // The transferred funds are held in the destination account
+                                let mut destination =
+                                    self.open_account(accounts.as_mut(), destination_id)?;
+                                let destination_before = Balances::of(&destination);
+                                destination.available -= amount;
+                                destination.held += amount;
+                                counterparty = Some((destination, destination_before));
+                                vec![
+                                    (LedgerAccount::Available(destination_id), -amount),
+                                    (LedgerAccount::Held(destination_id), amount),
+                                ]
+                            } else if disputed_transaction.transaction_type
+                                == TransactionType::Withdrawal
+                            {
+                                account.held += amount;
+                                // The withdrawn funds are provisionally credited back
+                                vec![
+                                    (LedgerAccount::Held(client_id), amount),
+                                    (LedgerAccount::Funding, -amount),
+                                ]
+                            } else {
+                                account.available -= amount;
+                                account.held += amount;
+                                vec![
+                                    (LedgerAccount::Available(client_id), -amount),
+                                    (LedgerAccount::Held(client_id), amount),
+                                ]
+                            };
+                        disputed_transaction.under_dispute = true;
+                        account
+                            .transactions
+                            .insert(disputed_transaction.transaction_id, disputed_transaction);
+                        Ok(postings)
+                    }
+                    None => Err(RejectionReason::UnknownTransaction),
+                }
             }
             TransactionType::Resolve => {
-                if let Some(mut disputed_transaction) = account
-                    .transactions
-                    .get(&transaction.transaction_id)
-                    .cloned()
-                {
+                // Transactions under dispute are never evicted
+                match accounts.get_transaction(transaction.client_id, transaction.transaction_id)? {
                     // Can only resolve a transaction that is under dispute
-                    if disputed_transaction.under_dispute {
-                        account.available += disputed_transaction.amount;
-                        account.held -= disputed_transaction.amount;
+                    Some(mut disputed_transaction) if disputed_transaction.under_dispute => {
+                        let amount = ledger::to_units(disputed_transaction.amount);
+                        // Transferred funds are released in the destination account
+                        let held_by = match disputed_transaction.destination_id {
+                            Some(destination_id) => {
+                                let mut destination =
+                                    self.open_account(accounts.as_mut(), destination_id)?;
+                                let destination_before = Balances::of(&destination);
+                                destination.available += amount;
+                                destination.held -= amount;
+                                counterparty = Some((destination, destination_before));
+                                destination_id
+                            }
+                            None => {
+                                account.available += amount;
+                                account.held -= amount;
+                                client_id
+                            }
+                        };
                         disputed_transaction.under_dispute = false;
                         account
                             .transactions
                             .insert(disputed_transaction.transaction_id, disputed_transaction);
+                        Ok(vec![
+                            (LedgerAccount::Held(held_by), -amount),
+                            (LedgerAccount::Available(held_by), amount),
+                        ])
                     }
+                    Some(disputed_transaction) if disputed_transaction.charged_back => {
+                        Err(RejectionReason::ChargedBack)
+                    }
+                    Some(_) => Err(RejectionReason::NotDisputed),
+                    None => Err(RejectionReason::UnknownTransaction),
                 }
             }
             TransactionType::Chargeback => {
-                if let Some(mut disputed_transaction) = account
-                    .transactions
-                    .get(&transaction.transaction_id)
-                    .cloned()
-                {
+                // Transactions under dispute are never evicted
+                match accounts.get_transaction(transaction.client_id, transaction.transaction_id)? {
                     // Can only chargeback a transaction that is under dispute
-                    if disputed_transaction.under_dispute {
-                        account.held -= disputed_transaction.amount;
+                    Some(mut disputed_transaction) if disputed_transaction.under_dispute => {
+                        let amount = ledger::to_units(disputed_transaction.amount);
+                        let postings = match disputed_transaction.destination_id {
+                            // A charged back transfer is reversed, the held funds go back
+                            // to the source account
+                            Some(destination_id) => {
+                                let mut destination =
+                                    self.open_account(accounts.as_mut(), destination_id)?;
+                                let destination_before = Balances::of(&destination);
+                                destination.held -= amount;
+                                account.available += amount;
+                                counterparty = Some((destination, destination_before));
+                                vec![
+                                    (LedgerAccount::Held(destination_id), -amount),
+                                    (LedgerAccount::Available(client_id), amount),
+                                ]
+                            }
+                            None => {
+                                account.held -= amount;
+                                vec![
+                                    (LedgerAccount::Held(client_id), -amount),
+                                    (LedgerAccount::ChargebackLoss, amount),
+                                ]
+                            }
+                        };
                         account.locked = true;
                         disputed_transaction.under_dispute = false;
+                        disputed_transaction.charged_back = true;
                         account
                             .transactions
                             .insert(disputed_transaction.transaction_id, disputed_transaction);
+                        Ok(postings)
+                    }
+                    Some(disputed_transaction) if disputed_transaction.charged_back => {
+                        Err(RejectionReason::ChargedBack)
                     }
+                    Some(_) => Err(RejectionReason::NotDisputed),
+                    None => Err(RejectionReason::UnknownTransaction),
                 }
             }
+        };
+
+        // The history is only read when the policy could evict something from it
+        let removed = if retention.is_bounded() {
+            let mut history = accounts.transactions(account.client_id)?;
+            history.extend(account.transactions.clone());
+            retention.enforce(account.client_id, history, now)?
+        } else {
+            Vec::new()
+        };
+        account
+            .transactions
+            .retain(|transaction_id, _| !removed.contains(transaction_id));
+
+        let after = Balances::of(&account);
+        // Both accounts of a transfer are written in one update so it is never half applied.
+        // Only the source's history changes, the destination just has new balances.
+        let mut updated = vec![account];
+        let counterparty = counterparty.map(|(other, other_before)| {
+            let other_after = Balances::of(&other);
+            let other_id = other.client_id;
+            updated.push(other);
+            (other_id, other_before, other_after)
+        });
+        accounts.update_balances(updated)?;
+        if !removed.is_empty() {
+            accounts.remove_transactions(client_id, &removed)?;
+        }
+        let postings = applied.as_deref().unwrap_or_default();
+        if !postings.is_empty() {
+            self.ledger
+                .lock()
+                .expect("Ignore lock poisoning")
+                .post(postings)?;
         }
 
-        accounts.insert(transaction.client_id, account);
-        Ok(())
+        let outcome = match applied {
+            Ok(_) => TransactionOutcome::Applied,
+            Err(reason) => TransactionOutcome::Rejected(reason),
+        };
+        self.stats.lock().expect("Ignore lock poisoning").record(
+            &transaction.transaction_type,
+            &outcome,
+            postings,
+        );
+        // Both accounts are reported before an observer error is returned
+        let mut notified = self.notify(&transaction, client_id, before, after, outcome);
+        if let Some((other_id, other_before, other_after)) = counterparty {
+            notified = notified.and(self.notify(
+                &transaction,
+                other_id,
+                other_before,
+                other_after,
+                outcome,
+            ));
+        }
+        notified?;
+        Ok(outcome)
+    }
+
+    // The balances of `client_id`, created if the client hasn't been seen yet. Balances the
+    // account had before the ledger followed it are its opening balances.
+    fn open_account(
+        &self,
+        accounts: &mut dyn AccountStore,
+        client_id: u16,
+    ) -> Result<Account, PaymentError> {
+        let account = match accounts.get_balances(client_id)? {
+            Some(account) => account,
+            None => accounts.create_account(client_id)?,
+        };
+        self.ledger
+            .lock()
+            .expect("Ignore lock poisoning")
+            .open_account(account.client_id, account.available, account.held);
+        Ok(account)
     }
 
-    pub fn get_account(&self, id: u16) -> Option<Account> {
+    fn notify(
+        &self,
+        transaction: &Transaction,
+        client_id: u16,
+        before: Balances,
+        after: Balances,
+        outcome: TransactionOutcome,
+    ) -> Result<(), PaymentError> {
+        let event = AccountEvent {
+            client_id,
+            transaction,
+            before,
+            after,
+            outcome,
+        };
+        // Every observer is called, the first error is returned
+        let mut notified = Ok(());
+        for observer in self
+            .observers
+            .lock()
+            .expect("Ignore lock poisoning")
+            .iter_mut()
+        {
+            notified = notified.and(observer.on_transaction(&event));
+        }
+        notified
+    }
+
+    pub fn get_account(&self, id: u16) -> Result<Option<Account>, PaymentError> {
         let accounts = self.accounts.lock().expect("Ignore lock poisoning");
-        return accounts.get(&id).cloned();
+        accounts.get_account(id)
     }
 
-    pub fn print_accounts(&self) {
-        println!("client,available,held,total,locked");
+    // Copy of every account, only meant for small account sets. Use `for_each_account`
+    // to go through all of them.
+    pub fn accounts(&self) -> Result<Vec<Account>, PaymentError> {
+        let mut accounts = Vec::new();
+        self.for_each_account(|account| {
+            accounts.push(account.clone());
+            Ok(())
+        })?;
+        Ok(accounts)
+    }
+
+    // Calls `f` with every account in client id order. The accounts are read from the store
+    // a page at a time so they never all have to be in memory, the lock is held throughout
+    // so they are consistent with each other.
+    pub fn for_each_account<F>(&self, mut f: F) -> Result<(), PaymentError>
+    where
+        F: FnMut(&Account) -> Result<(), PaymentError>,
+    {
         let accounts = self.accounts.lock().expect("Ignore lock poisoning");
-        for (_, account) in accounts.iter() {
-            println!(
-                "{},{},{},{},{}",
-                account.client_id,
-                account.available(),
-                account.held(),
-                account.total(),
-                account.locked
-            );
+        let mut after = None;
+        loop {
+            let page = accounts.accounts_after(after, ACCOUNTS_PAGE_SIZE)?;
+            for account in &page {
+                f(account)?;
+            }
+            match page.last() {
+                Some(account) if page.len() == ACCOUNTS_PAGE_SIZE => {
+                    after = Some(account.client_id)
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // The next page of accounts matching `query` after the client id `after`. The accounts
+    // are locked for each read of `query.page_size()` accounts from the store, not for the
+    // whole page, so processing can continue in between.
+    pub fn accounts_page(
+        &self,
+        query: &AccountQuery,
+        after: Option<u16>,
+    ) -> Result<AccountPage, PaymentError> {
+        let page_size = query.page_size();
+        let mut accounts = Vec::new();
+        let mut next = after;
+        loop {
+            let chunk = self
+                .accounts
+                .lock()
+                .expect("Ignore lock poisoning")
+                .accounts_after(next, page_size)?;
+            let exhausted = chunk.len() < page_size;
+            for account in chunk {
+                if accounts.len() == page_size {
+                    return Ok(AccountPage { accounts, next });
+                }
+                next = Some(account.client_id);
+                if query.matches(&account) {
+                    accounts.push(account);
+                }
+            }
+            if exhausted {
+                return Ok(AccountPage {
+                    accounts,
+                    next: None,
+                });
+            }
+            if accounts.len() == page_size {
+                return Ok(AccountPage { accounts, next });
+            }
+        }
+    }
+
+    // Every account matching `query`, read a page at a time
+    pub fn query(&self, query: &AccountQuery) -> Accounts {
+        Accounts::new(self.clone(), query.clone())
+    }
+
+    pub fn count(&self, query: &AccountQuery) -> Result<usize, PaymentError> {
+        self.query(query)
+            .try_fold(0, |count, account| account.map(|_| count + 1))
+    }
+
+    pub fn totals(&self, query: &AccountQuery) -> Result<AccountTotals, PaymentError> {
+        AccountTotals::of(self.query(query))
+    }
+
+    // In-memory copy of the accounts that can be processed against without affecting this
+    // service: it has no journal, observers or store and never writes spill files
+    pub fn scratch_copy(&self) -> Result<Self, PaymentError> {
+        let scratch = Self::new();
+        scratch.set_policy(self.policy());
+        scratch.set_retention(
+            self.retention
+                .lock()
+                .expect("Ignore lock poisoning")
+                .read_only(),
+        );
+        scratch.restore(self.accounts()?.into_iter().map(Ok))?;
+        Ok(scratch)
+    }
+
+    // Replaces all existing state with the given accounts, which are written to the store
+    // as they are read
+    pub fn restore<I>(&self, restored: I) -> Result<(), PaymentError>
+    where
+        I: IntoIterator<Item = Result<Account, PaymentError>>,
+    {
+        let mut accounts = self.accounts.lock().expect("Ignore lock poisoning");
+        accounts.clear()?;
+        // The restored balances become the opening balances of a fresh ledger
+        let mut ledger = Ledger::new();
+        let mut next_sequence = 0;
+        for account in restored {
+            let account = account?;
+            for transaction in account.transactions.values() {
+                next_sequence = next_sequence.max(transaction.sequence + 1);
+            }
+            ledger.open_account(account.client_id, account.available, account.held);
+            accounts.update_account(account)?;
+        }
+        self.sequence.store(next_sequence, Ordering::Relaxed);
+        *self.ledger.lock().expect("Ignore lock poisoning") = ledger;
+        Ok(())
+    }
+
+    // Also checks every client in the ledger against the balances of its account, so a
+    // balance that changed without a posting is caught
+    pub fn trial_balance(&self) -> Result<TrialBalance, PaymentError> {
+        let accounts = self.accounts.lock().expect("Ignore lock poisoning");
+        let ledger = self.ledger.lock().expect("Ignore lock poisoning");
+        let mut trial_balance = ledger.trial_balance();
+        for client_id in ledger.client_ids() {
+            let account = accounts
+                .get_balances(client_id)?
+                .unwrap_or_else(|| Account::new(&client_id));
+            trial_balance.reconcile(&ledger, &account);
         }
+        Ok(trial_balance)
+    }
+
+    pub fn ledger_balance(&self, account: LedgerAccount) -> f64 {
+        self.ledger
+            .lock()
+            .expect("Ignore lock poisoning")
+            .balance(account)
+    }
+
+    // Counters for everything processed so far, the locked accounts are counted on demand
+    pub fn run_stats(&self) -> Result<RunStats, PaymentError> {
+        let mut stats = self.stats.lock().expect("Ignore lock poisoning").clone();
+        let mut locked_accounts = 0;
+        self.for_each_account(|account| {
+            if account.locked() {
+                locked_accounts += 1;
+            }
+            Ok(())
+        })?;
+        stats.locked_accounts = locked_accounts;
+        Ok(stats)
+    }
+
+    pub(crate) fn record_rows_read(&self, rows: u64) {
+        let mut stats = self.stats.lock().expect("Ignore lock poisoning");
+        stats.rows_read += rows;
+        stats.touch();
+    }
+
+    pub(crate) fn record_parse_failure(&self) {
+        self.stats
+            .lock()
+            .expect("Ignore lock poisoning")
+            .parse_failures += 1;
+    }
+
+    // Every account, sorted by client id
+    pub fn print_accounts(&self) -> Result<(), PaymentError> {
+        self.print_accounts_with(&ReportOptions::default())
+    }
+
+    pub fn print_accounts_with(&self, options: &ReportOptions) -> Result<(), PaymentError> {
+        self.write_accounts(&mut io::stdout().lock(), OutputFormat::Csv, options)
+    }
+
+    pub fn write_accounts(
+        &self,
+        writer: &mut dyn Write,
+        format: OutputFormat,
+        options: &ReportOptions,
+    ) -> Result<(), PaymentError> {
+        let mut account_writer = format.writer();
+        account_writer.write_header(writer)?;
+        match options.sort {
+            // The store is read in client id order so accounts are written as they are read
+            SortOrder::ClientId => self.for_each_account(|account| {
+                if options.includes(account) {
+                    account_writer.write_account(account, writer)?;
+                }
+                Ok(())
+            })?,
+            // Only the balances of the accounts in the report are kept for sorting
+            SortOrder::Balance => {
+                let mut accounts = Vec::new();
+                self.for_each_account(|account| {
+                    if options.includes(account) {
+                        accounts.push(account.balances());
+                    }
+                    Ok(())
+                })?;
+                for account in options.apply(accounts) {
+                    account_writer.write_account(&account, writer)?;
+                }
+            }
+        }
+        account_writer.write_footer(writer)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::engine::policy::Rounding;
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_serde() {
+        let account_service = AccountService::new();
+        for transaction in [
+            Transaction::deposit(1, 1, 10.0).unwrap(),
+            Transaction::dispute(1, 1),
+        ] {
+            account_service
+                .process_transaction(transaction)
+                .await
+                .unwrap();
+        }
+        let account = account_service.get_account(1).unwrap().unwrap();
+        let json = serde_json::to_string(&account).unwrap();
+        assert_eq!(serde_json::from_str::<Account>(&json).unwrap(), account);
+
+        assert_eq!(
+            serde_json::to_string(&Transaction::chargeback(1, 2)).unwrap(),
+            "{\"transaction_type\":\"chargeback\",\"client_id\":1,\"transaction_id\":2,\
+             \"amount\":0.0,\"under_dispute\":false,\"charged_back\":false}"
+        );
+
+        // A deserialized transaction can't bring its dispute state in with it
+        let deposit: Transaction = serde_json::from_str(
+            "{\"transaction_type\":\"deposit\",\"client_id\":2,\"transaction_id\":3,\
+             \"amount\":5.0,\"under_dispute\":true,\"charged_back\":true}",
+        )
+        .unwrap();
+        account_service.process_transaction(deposit).await.unwrap();
+        assert_eq!(
+            account_service
+                .process_transaction(Transaction::dispute(2, 3))
+                .await
+                .unwrap(),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            serde_json::to_string(&TransactionOutcome::Rejected(
+                RejectionReason::InsufficientFunds
+            ))
+            .unwrap(),
+            "{\"rejected\":\"insufficient_funds\"}"
+        );
+    }
+
+    #[test]
+    fn test_process_csv() {
+        let account_service = AccountService::new();
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     withdrawal,1,2,4.0\n\
+                     dispute,1,1,\n";
+        account_service.process_csv(input.as_bytes()).unwrap();
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), -4.0);
+        assert_eq!(account.held(), 10.0);
+        assert_eq!(account_service.run_stats().unwrap().rows_read, 3);
+
+        assert!(account_service
+            .process_csv("type,client,tx,amount\nrefund,1,3,1.0\n".as_bytes())
+            .is_err());
+        assert_eq!(account_service.run_stats().unwrap().parse_failures, 1);
+        assert_eq!(
+            account_service
+                .apply(Transaction::withdrawal(1, 4, 1.0).unwrap())
+                .unwrap(),
+            TransactionOutcome::Rejected(RejectionReason::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_transaction_constructors() {
+        let deposit = Transaction::deposit(1, 2, 1.5).unwrap();
+        assert_eq!(deposit.transaction_type(), TransactionType::Deposit);
+        assert_eq!(deposit.client_id(), 1);
+        assert_eq!(deposit.transaction_id(), 2);
+        assert_eq!(deposit.amount(), Some(1.5));
+        assert_eq!(deposit, Transaction::from_str("deposit,1,2,1.5").unwrap());
+
+        let chargeback = Transaction::chargeback(1, 2);
+        assert_eq!(chargeback.amount(), None);
+        assert_eq!(chargeback.to_string(), "chargeback,1,2,");
+
+        assert!(Transaction::withdrawal(1, 3, -1.0).is_err());
+        assert!(Transaction::deposit(1, 3, 0.0).is_err());
+        assert!(Transaction::deposit(1, 3, f32::NAN).is_err());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let transfer = Transaction::transfer(1, 2, 3, 4.5).unwrap();
+        assert_eq!(transfer.destination_id(), Some(2));
+        assert_eq!(transfer.to_string(), "transfer,1,3,4.5,2");
+        assert_eq!(
+            transfer,
+            Transaction::from_str("transfer,1,3,4.5,2").unwrap()
+        );
+        assert!(Transaction::transfer(1, 1, 3, 4.5).is_err());
+        assert!(Transaction::transfer(1, 2, 3, -4.5).is_err());
+        assert!(Transaction::new(TransactionType::Transfer, 1, 3, 4.5).is_err());
+        assert!(Transaction::from_str("transfer,1,3,4.5,").is_err());
+
+        let account_service = AccountService::new();
+        for (transaction, outcome) in [
+            (
+                Transaction::deposit(1, 1, 10.0).unwrap(),
+                TransactionOutcome::Applied,
+            ),
+            (
+                Transaction::transfer(1, 2, 2, 20.0).unwrap(),
+                TransactionOutcome::Rejected(RejectionReason::InsufficientFunds),
+            ),
+            // Only rejected once processed so the rest of the input still is
+            (
+                Transaction::from_str("transfer,1,4,1.0,1").unwrap(),
+                TransactionOutcome::Rejected(RejectionReason::InvalidDestination),
+            ),
+            (transfer, TransactionOutcome::Applied),
+            (Transaction::dispute(1, 3), TransactionOutcome::Applied),
+        ] {
+            assert_eq!(account_service.apply(transaction).unwrap(), outcome);
+        }
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            5.5
+        );
+        let destination = account_service.get_account(2).unwrap().unwrap();
+        assert_eq!(destination.available(), 0.0);
+        assert_eq!(destination.held(), 4.5);
+
+        // Charging back the transfer returns the funds to the source
+        account_service
+            .apply(Transaction::chargeback(1, 3))
+            .unwrap();
+        let source = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(source.available(), 10.0);
+        assert!(source.locked());
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().total(),
+            0.0
+        );
+        assert!(account_service.trial_balance().unwrap().is_balanced());
+        assert_eq!(
+            account_service.ledger_balance(LedgerAccount::Available(2)),
+            0.0
+        );
+        assert_eq!(account_service.run_stats().unwrap().transferred(), 4.5);
+    }
+
+    #[test]
+    fn test_transfer_without_destination_is_not_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let account_service = AccountService::new();
+        account_service.set_journal(Journal::open(&dir.path().join("journal.csv")).unwrap());
+        let position = account_service.journal_position().unwrap();
+
+        // Only a deserialized transfer can be missing its destination
+        let mut transfer = Transaction::transfer(1, 2, 1, 1.0).unwrap();
+        transfer.destination_id = None;
+        assert!(matches!(
+            account_service.apply(transfer),
+            Err(PaymentError::ParseError(_))
+        ));
+        assert_eq!(account_service.journal_position().unwrap(), position);
+        assert!(account_service.get_account(1).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_amounts_are_rejected() {
+        // Parsed rows are only rejected once processed so the rest of the input still is
+        let account_service = AccountService::new();
+        account_service.set_policy(Policy {
+            rounding: Rounding::Truncate,
+            ..Policy::default()
+        });
+        for row in [
+            "deposit,1,3,-2.0",
+            "deposit,1,4,0.00004",
+            "withdrawal,1,5,NaN",
+        ] {
+            assert_eq!(
+                account_service
+                    .process_transaction(Transaction::from_str(row).unwrap())
+                    .await
+                    .unwrap(),
+                TransactionOutcome::Rejected(RejectionReason::InvalidAmount)
+            );
+        }
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            0.0
+        );
+    }
 
     #[tokio::test]
     async fn test_precision_truncated_at_4() {
@@ -302,9 +1468,18 @@ mod tests {
             .process_transaction(Transaction::from_str("deposit,3,3,0.12").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().total(), 0.1235);
-        assert_eq!(account_service.get_account(2).unwrap().total(), 0.1234);
-        assert_eq!(account_service.get_account(3).unwrap().total(), 0.12);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            0.1235
+        );
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().total(),
+            0.1234
+        );
+        assert_eq!(
+            account_service.get_account(3).unwrap().unwrap().total(),
+            0.12
+        );
     }
 
     #[tokio::test]
@@ -314,12 +1489,18 @@ mod tests {
             .process_transaction(Transaction::from_str("deposit,1,1,99.0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 99.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            99.0
+        );
         account_service
             .process_transaction(Transaction::from_str("dispute,1,2,0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 99.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            99.0
+        );
     }
 
     #[tokio::test]
@@ -329,12 +1510,18 @@ mod tests {
             .process_transaction(Transaction::from_str("deposit,1,1,99.0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 99.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            99.0
+        );
         account_service
             .process_transaction(Transaction::from_str("dispute,2,1,0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 99.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            99.0
+        );
     }
 
     #[tokio::test]
@@ -344,7 +1531,10 @@ mod tests {
             .process_transaction(Transaction::from_str("deposit,1,1,99").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 99.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            99.0
+        );
     }
 
     #[tokio::test]
@@ -358,10 +1548,22 @@ mod tests {
             .process_transaction(Transaction::from_str("dispute,1,1,").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().held(), 99.0);
-        assert_eq!(account_service.get_account(1).unwrap().total(), 99.0);
-        assert_eq!(account_service.get_account(1).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            0.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().held(),
+            99.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            99.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().locked(),
+            false
+        );
     }
 
     #[tokio::test]
@@ -375,15 +1577,30 @@ mod tests {
             .process_transaction(Transaction::from_str("withdrawal,1,2,50.0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().total(), 50.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            50.0
+        );
         account_service
             .process_transaction(Transaction::from_str("dispute,1,2,50.0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 50.0);
-        assert_eq!(account_service.get_account(1).unwrap().held(), 50.0);
-        assert_eq!(account_service.get_account(1).unwrap().total(), 100.0);
-        assert_eq!(account_service.get_account(1).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            50.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().held(),
+            50.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            100.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().locked(),
+            false
+        );
     }
 
     #[tokio::test]
@@ -401,10 +1618,22 @@ mod tests {
             .process_transaction(Transaction::from_str("dispute,1,1,").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().held(), 99.0);
-        assert_eq!(account_service.get_account(1).unwrap().total(), 99.0);
-        assert_eq!(account_service.get_account(1).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            0.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().held(),
+            99.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            99.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().locked(),
+            false
+        );
     }
 
     #[tokio::test]
@@ -418,22 +1647,177 @@ mod tests {
             .process_transaction(Transaction::from_str("dispute,1,1,").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().held(), 10.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().held(),
+            10.0
+        );
         account_service
             .process_transaction(Transaction::from_str("chargeback,1,1,").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().available(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().held(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().total(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().locked(), true);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            0.0
+        );
+        assert_eq!(account_service.get_account(1).unwrap().unwrap().held(), 0.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            0.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().locked(),
+            true
+        );
 
         // Cannot deposit if account locked
         account_service
             .process_transaction(Transaction::from_str("deposit,1,1,99.0").unwrap())
             .await
             .unwrap();
-        assert_eq!(account_service.get_account(1).unwrap().total(), 0.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            0.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ledger_balances() {
+        let account_service = AccountService::new();
+        for line in [
+            "deposit,1,1,10.0",
+            "deposit,2,2,5.5",
+            "withdrawal,1,3,2.5",
+            "withdrawal,2,4,100.0",
+            "dispute,1,3,",
+            "resolve,1,3,",
+            "dispute,2,2,",
+            "chargeback,2,2,",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        assert!(account_service.trial_balance().unwrap().is_balanced());
+        for account in account_service.accounts().unwrap() {
+            assert_eq!(
+                account_service.ledger_balance(LedgerAccount::Available(account.client_id)),
+                account.available() as f64
+            );
+            assert_eq!(
+                account_service.ledger_balance(LedgerAccount::Held(account.client_id)),
+                account.held() as f64
+            );
+        }
+        assert_eq!(
+            account_service.ledger_balance(LedgerAccount::ChargebackLoss),
+            5.5
+        );
+
+        // A balance changed without a posting is caught
+        let mut account = account_service.get_account(1).unwrap().unwrap();
+        account.available += ledger::to_units(1.0);
+        account_service
+            .accounts
+            .lock()
+            .unwrap()
+            .update_account(account)
+            .unwrap();
+        let trial_balance = account_service.trial_balance().unwrap();
+        assert!(!trial_balance.is_balanced());
+        assert_eq!(trial_balance.total, 0.0);
+        assert_eq!(
+            trial_balance.mismatches[0].account,
+            LedgerAccount::Available(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_many_small_deposits_stay_exact() {
+        let account_service = AccountService::new();
+        for transaction_id in 0..200_000 {
+            account_service
+                .process_transaction(
+                    Transaction::from_str(&format!("deposit,1,{},0.1", transaction_id)).unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            20_000.0
+        );
+        assert!(account_service.trial_balance().unwrap().is_balanced());
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let account_service = AccountService::new();
+        account_service.set_policy(Policy {
+            allow_withdrawal_disputes: false,
+            locked_accounts_reject_all: false,
+            rounding: Rounding::Truncate,
+        });
+        for line in [
+            "deposit,1,1,10.00009",
+            "withdrawal,1,2,1.0",
+            "dispute,1,2,",
+            "dispute,1,1,",
+            "chargeback,1,1,",
+            "deposit,1,3,5.0",
+            "withdrawal,1,4,1.0",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let stats = account_service.run_stats().unwrap();
+        assert_eq!(stats.rejected[&RejectionReason::DisputeNotAllowed], 1);
+        assert_eq!(stats.rejected[&RejectionReason::AccountLocked], 1);
+        // The chargeback took back the truncated deposit, the locked account still
+        // accepted the second deposit
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 4.0);
+        assert_eq!(account.held(), 0.0);
+        assert!(account.locked());
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_is_final() {
+        // Locked accounts still accept disputes so only the charged back state stops the
+        // same deposit from being charged back twice
+        let account_service = AccountService::new();
+        account_service.set_policy(Policy {
+            locked_accounts_reject_all: false,
+            ..Default::default()
+        });
+        for line in [
+            "deposit,1,1,10.0",
+            "deposit,1,2,5.0",
+            "dispute,1,1,",
+            "chargeback,1,1,",
+            "dispute,1,1,",
+            "resolve,1,1,",
+            "chargeback,1,1,",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let stats = account_service.run_stats().unwrap();
+        assert_eq!(stats.rejected[&RejectionReason::ChargedBack], 3);
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 5.0);
+        assert_eq!(account.held(), 0.0);
+        assert!(account.locked());
+        assert!(account.transactions[&1].charged_back);
+        assert!(account_service.trial_balance().unwrap().is_balanced());
     }
 
     #[tokio::test]
@@ -0,0 +1,97 @@
+use crate::engine::errors::PaymentError;
+use std::str::FromStr;
+
+// Decimal places kept for every amount
+const DECIMAL_PLACES: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Rounding {
+    // Amounts are kept as they are, balances are only rounded to 4 decimal places on output
+    #[default]
+    None,
+    // To the nearest value with 4 decimal places
+    Round,
+    // Extra decimal places are dropped
+    Truncate,
+}
+
+impl FromStr for Rounding {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Rounding::None),
+            "round" => Ok(Rounding::Round),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(PaymentError::ConfigError(format!(
+                "Invalid rounding: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl Rounding {
+    pub fn apply(&self, amount: f32) -> f32 {
+        match self {
+            Rounding::None => amount,
+            Rounding::Round => format!("{:.*}", DECIMAL_PLACES, amount)
+                .parse()
+                .unwrap_or(amount),
+            // Done on the shortest representation of the amount, scaling the float
+            // would turn e.g. 0.7 into 0.6999
+            Rounding::Truncate => {
+                let s = amount.to_string();
+                match s.split_once('.') {
+                    Some((whole, fraction)) if fraction.len() > DECIMAL_PLACES => {
+                        format!("{}.{}", whole, &fraction[..DECIMAL_PLACES])
+                            .parse()
+                            .unwrap_or(amount)
+                    }
+                    _ => amount,
+                }
+            }
+        }
+    }
+}
+
+// Rules `process_transaction` applies that vary between deployments. The defaults are
+// the engine's original behavior.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Policy {
+    // Withdrawals can be disputed, which holds the withdrawn amount until resolved
+    pub allow_withdrawal_disputes: bool,
+    // Locked accounts reject every transaction, otherwise they only reject withdrawals
+    pub locked_accounts_reject_all: bool,
+    // Applied to deposit and withdrawal amounts before they are processed
+    pub rounding: Rounding,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            allow_withdrawal_disputes: true,
+            locked_accounts_reject_all: true,
+            rounding: Rounding::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(Rounding::None.apply(0.00004), 0.00004);
+        assert_eq!(Rounding::Round.apply(0.12345), 0.1235);
+        assert_eq!(Rounding::Round.apply(2.0), 2.0);
+        assert_eq!(Rounding::Truncate.apply(0.12345), 0.1234);
+        assert_eq!(Rounding::Truncate.apply(0.7), 0.7);
+        assert_eq!(Rounding::Truncate.apply(-1.99999), -1.9999);
+        assert!(Rounding::from_str("ceil").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::ledger::{from_units, to_units};
+use crate::engine::payments::{Account, AccountService};
+
+const DEFAULT_PAGE_SIZE: usize = 1000;
+
+// Which accounts to read from `AccountService::query` and friends, every account by default.
+// Balance bounds are inclusive.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccountQuery {
+    pub locked: Option<bool>,
+    pub with_held_funds: bool,
+    pub min_available: Option<f32>,
+    pub max_available: Option<f32>,
+    pub min_total: Option<f32>,
+    pub max_total: Option<f32>,
+    // Accounts per page, also how many are read from the store per lock of the accounts.
+    // Read through `page_size` which never returns 0.
+    page_size: usize,
+}
+
+impl Default for AccountQuery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccountQuery {
+    pub fn new() -> Self {
+        Self {
+            locked: None,
+            with_held_funds: false,
+            min_available: None,
+            max_available: None,
+            min_total: None,
+            max_total: None,
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+
+    pub fn with_locked(mut self, locked: bool) -> Self {
+        self.locked = Some(locked);
+        self
+    }
+
+    pub fn with_held_funds(mut self) -> Self {
+        self.with_held_funds = true;
+        self
+    }
+
+    pub fn with_available_between(mut self, min: Option<f32>, max: Option<f32>) -> Self {
+        self.min_available = min;
+        self.max_available = max;
+        self
+    }
+
+    pub fn with_total_between(mut self, min: Option<f32>, max: Option<f32>) -> Self {
+        self.min_total = min;
+        self.max_total = max;
+        self
+    }
+
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    // A deserialized query can bypass `with_page_size`, so it is checked here too
+    pub fn page_size(&self) -> usize {
+        self.page_size.max(1)
+    }
+
+    pub fn matches(&self, account: &Account) -> bool {
+        let within = |value: f32, min: Option<f32>, max: Option<f32>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        self.locked.is_none_or(|locked| account.locked() == locked)
+            && (!self.with_held_funds || account.held() > 0.0)
+            && within(account.available(), self.min_available, self.max_available)
+            && within(account.total(), self.min_total, self.max_total)
+    }
+}
+
+// Up to `page_size` matching accounts in client id order. `next` is passed back to
+// `AccountService::accounts_page` for the following page, None once there are no more.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccountPage {
+    pub accounts: Vec<Account>,
+    pub next: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AccountTotals {
+    pub accounts: usize,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+}
+
+impl AccountTotals {
+    // Summed in whole units so the totals don't drift with the number of accounts
+    pub(crate) fn of(
+        accounts: impl Iterator<Item = Result<Account, PaymentError>>,
+    ) -> Result<Self, PaymentError> {
+        let mut count = 0;
+        let (mut available, mut held) = (0, 0);
+        for account in accounts {
+            let account = account?;
+            count += 1;
+            available += to_units(account.available());
+            held += to_units(account.held());
+        }
+        Ok(Self {
+            accounts: count,
+            available: from_units(available),
+            held: from_units(held),
+            total: from_units(available + held),
+        })
+    }
+}
+
+// Matching accounts in client id order, read a page at a time so the accounts are only
+// locked while each page is read. Accounts changed between pages are seen as they are
+// when their page is read. Stops after the first error reading from the store.
+pub struct Accounts {
+    account_service: AccountService,
+    query: AccountQuery,
+    page: std::vec::IntoIter<Account>,
+    next: Option<u16>,
+    done: bool,
+}
+
+impl Accounts {
+    pub(crate) fn new(account_service: AccountService, query: AccountQuery) -> Self {
+        Self {
+            account_service,
+            query,
+            page: Vec::new().into_iter(),
+            next: None,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Accounts {
+    type Item = Result<Account, PaymentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(account) = self.page.next() {
+                return Some(Ok(account));
+            }
+            if self.done {
+                return None;
+            }
+            let page = match self.account_service.accounts_page(&self.query, self.next) {
+                Ok(page) => page,
+                Err(error) => {
+                    self.done = true;
+                    return Some(Err(error));
+                }
+            };
+            self.done = page.next.is_none();
+            self.next = page.next;
+            self.page = page.accounts.into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::payments::Transaction;
+
+    #[tokio::test]
+    async fn test_query() {
+        let account_service = AccountService::new();
+        for client_id in 1..=10 {
+            account_service
+                .process_transaction(
+                    Transaction::deposit(client_id, client_id as u32, client_id as f32).unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+        for transaction in [
+            Transaction::dispute(3, 3),
+            Transaction::dispute(4, 4),
+            Transaction::chargeback(4, 4),
+        ] {
+            account_service
+                .process_transaction(transaction)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(AccountQuery::new().with_page_size(0).page_size(), 1);
+        let query = AccountQuery::new().with_page_size(3);
+        let client_ids: Vec<u16> = account_service
+            .query(&query)
+            .map(|account| account.unwrap().client_id())
+            .collect();
+        assert_eq!(client_ids, (1..=10).collect::<Vec<u16>>());
+
+        let held = AccountQuery::new().with_held_funds();
+        assert_eq!(account_service.count(&held).unwrap(), 1);
+        assert_eq!(
+            account_service
+                .count(&AccountQuery::new().with_locked(true))
+                .unwrap(),
+            1
+        );
+
+        let query = AccountQuery::new()
+            .with_locked(false)
+            .with_total_between(Some(2.0), Some(8.0))
+            .with_page_size(2);
+        let page = account_service.accounts_page(&query, None).unwrap();
+        assert_eq!(page.accounts.len(), 2);
+        assert_eq!(page.next, Some(3));
+        let page = account_service.accounts_page(&query, page.next).unwrap();
+        assert_eq!(
+            page.accounts
+                .iter()
+                .map(|account| account.client_id())
+                .collect::<Vec<u16>>(),
+            vec![5, 6]
+        );
+
+        let totals = account_service
+            .totals(&AccountQuery::new().with_locked(false))
+            .unwrap();
+        assert_eq!(totals.accounts, 9);
+        assert_eq!(totals.available, 48.0);
+        assert_eq!(totals.held, 3.0);
+        assert_eq!(totals.total, 51.0);
+    }
+}
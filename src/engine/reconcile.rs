@@ -0,0 +1,247 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::Account;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+// A closing balance as reported by a third party, in the same format as the csv output
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExpectedBalance {
+    pub client_id: u16,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+}
+
+impl FromStr for ExpectedBalance {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 5 {
+            return Err(PaymentError::ParseError(format!(
+                "Invalid expected balance: {}",
+                s
+            )));
+        }
+        Ok(Self {
+            client_id: parse_field(fields[0], s)?,
+            available: parse_field(fields[1], s)?,
+            held: parse_field(fields[2], s)?,
+            total: parse_field(fields[3], s)?,
+            locked: parse_field(fields[4], s)?,
+        })
+    }
+}
+
+// Reads a `client,available,held,total,locked` file, the header is required
+pub fn read_expected_balances<R: BufRead>(reader: R) -> Result<Vec<ExpectedBalance>, PaymentError> {
+    let context = "Could not read expected balances";
+    let mut lines = reader.lines();
+    match lines
+        .next()
+        .transpose()
+        .map_err(PaymentError::source_error(context))?
+    {
+        Some(header) if header.trim() == "client,available,held,total,locked" => {}
+        _ => {
+            return Err(PaymentError::ParseError(
+                "Expected balances must start with a client,available,held,total,locked header"
+                    .to_string(),
+            ))
+        }
+    }
+
+    let mut expected = Vec::new();
+    for line in lines {
+        let line = line.map_err(PaymentError::source_error(context))?;
+        if !line.trim().is_empty() {
+            expected.push(ExpectedBalance::from_str(&line)?);
+        }
+    }
+    Ok(expected)
+}
+
+// A single field of a single client that doesn't match. A client missing on one side is
+// reported once with the "account" field.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Discrepancy {
+    pub client_id: u16,
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+    // actual - expected, only for balances
+    pub difference: Option<f32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReconciliationReport {
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl ReconciliationReport {
+    pub fn new(accounts: &[Account], expected: &[ExpectedBalance]) -> Self {
+        let clients = pair_by_client(
+            accounts,
+            |account| account.client_id,
+            expected,
+            |balance| balance.client_id,
+        );
+
+        let mut discrepancies = Vec::new();
+        for (client_id, pair) in clients {
+            match pair {
+                (Some(account), Some(balance)) => {
+                    for (field, actual, expected) in [
+                        ("available", account.available(), balance.available),
+                        ("held", account.held(), balance.held),
+                        ("total", account.total(), balance.total),
+                    ] {
+                        if actual != expected {
+                            discrepancies.push(Discrepancy {
+                                client_id,
+                                field: field.to_string(),
+                                expected: expected.to_string(),
+                                actual: actual.to_string(),
+                                difference: Some(round(actual - expected)),
+                            });
+                        }
+                    }
+                    if account.locked() != balance.locked {
+                        discrepancies.push(Discrepancy {
+                            client_id,
+                            field: "locked".to_string(),
+                            expected: balance.locked.to_string(),
+                            actual: account.locked().to_string(),
+                            difference: None,
+                        });
+                    }
+                }
+                (Some(_), None) => discrepancies.push(missing(client_id, "missing", "present")),
+                (None, Some(_)) => discrepancies.push(missing(client_id, "present", "missing")),
+                (None, None) => {}
+            }
+        }
+        Self { discrepancies }
+    }
+
+    pub fn is_reconciled(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "client,field,expected,actual,difference").map_err(write_error)?;
+        for discrepancy in &self.discrepancies {
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                discrepancy.client_id,
+                discrepancy.field,
+                discrepancy.expected,
+                discrepancy.actual,
+                discrepancy
+                    .difference
+                    .map(|difference| difference.to_string())
+                    .unwrap_or_default()
+            )
+            .map_err(write_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn missing(client_id: u16, expected: &str, actual: &str) -> Discrepancy {
+    Discrepancy {
+        client_id,
+        field: "account".to_string(),
+        expected: expected.to_string(),
+        actual: actual.to_string(),
+        difference: None,
+    }
+}
+
+// Both sides keyed by client id, in client id order, so the order they were read in
+// doesn't matter and a client missing from one side has None there
+pub(crate) fn pair_by_client<'a, L, R>(
+    left: &'a [L],
+    left_id: fn(&L) -> u16,
+    right: &'a [R],
+    right_id: fn(&R) -> u16,
+) -> BTreeMap<u16, (Option<&'a L>, Option<&'a R>)> {
+    let mut clients: BTreeMap<u16, (Option<&L>, Option<&R>)> = BTreeMap::new();
+    for item in left {
+        clients.entry(left_id(item)).or_default().0 = Some(item);
+    }
+    for item in right {
+        clients.entry(right_id(item)).or_default().1 = Some(item);
+    }
+    clients
+}
+
+// Same precision as the account balances
+pub(crate) fn round(amount: f32) -> f32 {
+    format!("{:.4}", amount).parse().unwrap_or(amount)
+}
+
+fn parse_field<T: FromStr>(field: &str, line: &str) -> Result<T, PaymentError> {
+    field
+        .parse()
+        .map_err(|_| PaymentError::ParseError(format!("Invalid expected balance: {}", line)))
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the reconciliation report")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ledger;
+
+    #[test]
+    fn test_reconcile() {
+        let mut first = Account::new(&1);
+        first.available = ledger::to_units(1.5);
+        let mut second = Account::new(&2);
+        second.held = ledger::to_units(2.0);
+        second.locked = true;
+        let third = Account::new(&3);
+
+        let expected = read_expected_balances(
+            "client,available,held,total,locked\n\
+             1,1.5,0,1.5,false\n\
+             2,0,1.75,1.75,false\n\
+             4,1,0,1,false\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let report = ReconciliationReport::new(&[first.clone(), second, third], &expected);
+        assert!(!report.is_reconciled());
+
+        let mut csv = Vec::new();
+        report.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "client,field,expected,actual,difference\n\
+             2,held,1.75,2,0.25\n\
+             2,total,1.75,2,0.25\n\
+             2,locked,false,true,\n\
+             3,account,missing,present,\n\
+             4,account,present,missing,\n"
+        );
+
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::from_str::<ReconciliationReport>(&serde_json::to_string(&report).unwrap())
+                .unwrap(),
+            report
+        );
+
+        assert!(ReconciliationReport::new(&[first], &expected[..1]).is_reconciled());
+        assert!(read_expected_balances("1,1.5,0,1.5,false\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::Account;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SortOrder {
+    // Ascending client id
+    #[default]
+    ClientId,
+    // Highest total balance first, ties broken by client id
+    Balance,
+}
+
+impl FromStr for SortOrder {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortOrder::ClientId),
+            "balance" => Ok(SortOrder::Balance),
+            _ => Err(PaymentError::CliError(format!("Invalid sort order: {}", s))),
+        }
+    }
+}
+
+// Which accounts end up in the report and in what order. The defaults give every
+// account sorted by client id so the output is the same from run to run.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReportOptions {
+    pub sort: SortOrder,
+    pub only_locked: bool,
+    pub only_non_zero: bool,
+}
+
+impl ReportOptions {
+    pub fn includes(&self, account: &Account) -> bool {
+        (!self.only_locked || account.locked())
+            && (!self.only_non_zero || account.available() != 0.0 || account.held() != 0.0)
+    }
+
+    pub fn apply(&self, mut accounts: Vec<Account>) -> Vec<Account> {
+        accounts.retain(|account| self.includes(account));
+        match self.sort {
+            SortOrder::ClientId => accounts.sort_by_key(|account| account.client_id),
+            SortOrder::Balance => accounts.sort_by(|a, b| {
+                b.total()
+                    .total_cmp(&a.total())
+                    .then(a.client_id.cmp(&b.client_id))
+            }),
+        }
+        accounts
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    JsonLines,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = PaymentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(PaymentError::CliError(format!(
+                "Invalid output format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn writer(&self) -> Box<dyn AccountWriter> {
+        match self {
+            OutputFormat::Csv => Box::new(CsvWriter {}),
+            OutputFormat::Json => Box::new(JsonWriter::default()),
+            OutputFormat::JsonLines => Box::new(JsonLinesWriter {}),
+            OutputFormat::Table => Box::new(TableWriter::default()),
+        }
+    }
+}
+
+// Writes the report an account at a time so the accounts never all have to be in memory
+pub trait AccountWriter {
+    fn write_header(&mut self, _writer: &mut dyn Write) -> Result<(), PaymentError> {
+        Ok(())
+    }
+
+    fn write_account(
+        &mut self,
+        account: &Account,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentError>;
+
+    fn write_footer(&mut self, _writer: &mut dyn Write) -> Result<(), PaymentError> {
+        Ok(())
+    }
+
+    fn write_accounts(
+        &mut self,
+        accounts: &[Account],
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentError> {
+        self.write_header(writer)?;
+        for account in accounts {
+            self.write_account(account, writer)?;
+        }
+        self.write_footer(writer)
+    }
+}
+
+pub struct CsvWriter {}
+
+impl AccountWriter for CsvWriter {
+    fn write_header(&mut self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "client,available,held,total,locked").map_err(write_error)?;
+        Ok(())
+    }
+
+    fn write_account(
+        &mut self,
+        account: &Account,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentError> {
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            account.client_id,
+            account.available(),
+            account.held(),
+            account.total(),
+            account.locked()
+        )
+        .map_err(write_error)?;
+        Ok(())
+    }
+}
+
+// Single json array of account objects
+#[derive(Default)]
+pub struct JsonWriter {
+    // Whether an account was written yet, every other one is preceded by a comma
+    written: bool,
+}
+
+impl AccountWriter for JsonWriter {
+    fn write_header(&mut self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        write!(writer, "[").map_err(write_error)?;
+        Ok(())
+    }
+
+    fn write_account(
+        &mut self,
+        account: &Account,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentError> {
+        if self.written {
+            write!(writer, ",").map_err(write_error)?;
+        }
+        write!(writer, "{}", json_object(account)).map_err(write_error)?;
+        self.written = true;
+        Ok(())
+    }
+
+    fn write_footer(&mut self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "]").map_err(write_error)?;
+        Ok(())
+    }
+}
+
+// One json object per line
+pub struct JsonLinesWriter {}
+
+impl AccountWriter for JsonLinesWriter {
+    fn write_account(
+        &mut self,
+        account: &Account,
+        writer: &mut dyn Write,
+    ) -> Result<(), PaymentError> {
+        writeln!(writer, "{}", json_object(account)).map_err(write_error)?;
+        Ok(())
+    }
+}
+
+// Human readable table with every column padded to its widest value, so unlike the other
+// formats the rows are only written once every account has been seen
+#[derive(Default)]
+pub struct TableWriter {
+    rows: Vec<[String; 5]>,
+}
+
+impl AccountWriter for TableWriter {
+    fn write_account(
+        &mut self,
+        account: &Account,
+        _writer: &mut dyn Write,
+    ) -> Result<(), PaymentError> {
+        self.rows.push([
+            account.client_id.to_string(),
+            account.available().to_string(),
+            account.held().to_string(),
+            account.total().to_string(),
+            account.locked().to_string(),
+        ]);
+        Ok(())
+    }
+
+    fn write_footer(&mut self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        let header = ["client", "available", "held", "total", "locked"].map(String::from);
+        let mut widths = [0; 5];
+        for row in std::iter::once(&header).chain(&self.rows) {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.len());
+            }
+        }
+        for row in std::iter::once(&header).chain(&self.rows) {
+            let line: Vec<String> = row
+                .iter()
+                .zip(widths)
+                .map(|(value, width)| format!("{:>width$}", value, width = width))
+                .collect();
+            writeln!(writer, "{}", line.join("  ")).map_err(write_error)?;
+        }
+        Ok(())
+    }
+}
+
+fn json_object(account: &Account) -> String {
+    format!(
+        "{{\"client\":{},\"available\":{},\"held\":{},\"total\":{},\"locked\":{}}}",
+        account.client_id,
+        account.available(),
+        account.held(),
+        account.total(),
+        account.locked()
+    )
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the accounts report")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ledger;
+
+    fn account(client_id: u16, available: f32, locked: bool) -> Account {
+        let mut account = Account::new(&client_id);
+        account.available = ledger::to_units(available);
+        account.locked = locked;
+        account
+    }
+
+    fn client_ids(accounts: Vec<Account>) -> Vec<u16> {
+        accounts.iter().map(|account| account.client_id).collect()
+    }
+
+    #[test]
+    fn test_report_options() {
+        let accounts = vec![
+            account(3, 5.0, false),
+            account(1, 0.0, true),
+            account(4, 5.0, true),
+            account(2, 7.5, false),
+        ];
+
+        let sorted = ReportOptions::default().apply(accounts.clone());
+        assert_eq!(client_ids(sorted), vec![1, 2, 3, 4]);
+
+        let by_balance = ReportOptions {
+            sort: SortOrder::Balance,
+            ..Default::default()
+        }
+        .apply(accounts.clone());
+        assert_eq!(client_ids(by_balance), vec![2, 3, 4, 1]);
+
+        let locked = ReportOptions {
+            only_locked: true,
+            ..Default::default()
+        }
+        .apply(accounts.clone());
+        assert_eq!(client_ids(locked), vec![1, 4]);
+
+        let non_zero_locked = ReportOptions {
+            only_locked: true,
+            only_non_zero: true,
+            ..Default::default()
+        }
+        .apply(accounts);
+        assert_eq!(client_ids(non_zero_locked), vec![4]);
+    }
+
+    fn write(format: OutputFormat, accounts: &[Account]) -> String {
+        let mut buffer = Vec::new();
+        format
+            .writer()
+            .write_accounts(accounts, &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_output_formats() {
+        let accounts = vec![account(1, 1.5, false), account(12, 100.0, true)];
+
+        assert_eq!(
+            write(OutputFormat::Csv, &accounts),
+            "client,available,held,total,locked\n1,1.5,0,1.5,false\n12,100,0,100,true\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Json, &accounts),
+            "[{\"client\":1,\"available\":1.5,\"held\":0,\"total\":1.5,\"locked\":false},\
+             {\"client\":12,\"available\":100,\"held\":0,\"total\":100,\"locked\":true}]\n"
+        );
+        assert_eq!(
+            write(OutputFormat::JsonLines, &accounts),
+            "{\"client\":1,\"available\":1.5,\"held\":0,\"total\":1.5,\"locked\":false}\n\
+             {\"client\":12,\"available\":100,\"held\":0,\"total\":100,\"locked\":true}\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Table, &accounts),
+            "client  available  held  total  locked\n     1        1.5     0    1.5   false\n    12        100     0    100    true\n"
+        );
+        assert_eq!(write(OutputFormat::Json, &[]), "[]\n");
+    }
+}
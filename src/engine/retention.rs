@@ -0,0 +1,319 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::Transaction;
+use crate::engine::snapshot::{parse_transaction_record, write_transaction_record};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Controls how many deposits and withdrawals each account keeps around for disputes.
+// Transactions under dispute are always kept, otherwise the held funds could never be released.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    // Most stored transactions kept per account, the oldest are evicted first
+    pub max_count: Option<usize>,
+    // Stored transactions older than this are evicted
+    pub max_age: Option<Duration>,
+    // Transactions older than this can no longer be disputed so they are dropped
+    // entirely instead of being evicted
+    pub dispute_window: Option<Duration>,
+}
+
+// Evicted transactions are lost unless a spill directory is configured, in which case
+// they are written to disk and can still be found when they are disputed
+#[derive(Default)]
+pub struct Retention {
+    policy: RetentionPolicy,
+    spill: Option<Spill>,
+}
+
+impl Retention {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        Self {
+            policy,
+            spill: None,
+        }
+    }
+
+    pub fn with_spill(mut self, dir: &Path) -> Result<Self, PaymentError> {
+        fs::create_dir_all(dir).map_err(PaymentError::storage_error(format!(
+            "Could not create spill directory {}",
+            dir.display()
+        )))?;
+        self.spill = Some(Spill {
+            dir: dir.to_path_buf(),
+        });
+        Ok(self)
+    }
+
+    // Whether the policy can evict anything at all, otherwise there is no need to look at
+    // the stored history
+    pub(crate) fn is_bounded(&self) -> bool {
+        self.policy.max_count.is_some()
+            || self.policy.max_age.is_some()
+            || self.policy.dispute_window.is_some()
+    }
+
+    // The same retention for processing without writing to the spill directory, e.g. a
+    // statement or a dry run. Spilled transactions can still be found, and since they always
+    // can be that is the same as never evicting anything, only the dispute window applies.
+    pub fn read_only(&self) -> Retention {
+        match &self.spill {
+            Some(spill) => Retention {
+                policy: RetentionPolicy {
+                    dispute_window: self.policy.dispute_window,
+                    ..Default::default()
+                },
+                spill: Some(Spill {
+                    dir: spill.dir.clone(),
+                }),
+            },
+            None => Retention::new(self.policy.clone()),
+        }
+    }
+
+    // Picks the transactions to remove from the stored history of an account and returns
+    // their ids. Evicted transactions are spilled first if a spill directory is configured.
+    pub(crate) fn enforce(
+        &self,
+        client_id: u16,
+        mut transactions: HashMap<u32, Transaction>,
+        now: u64,
+    ) -> Result<Vec<u32>, PaymentError> {
+        let mut removed = Vec::new();
+        if let Some(dispute_window) = self.policy.dispute_window {
+            removed.extend(
+                transactions
+                    .values()
+                    .filter(|transaction| {
+                        !transaction.under_dispute
+                            && is_older_than(transaction, dispute_window, now)
+                    })
+                    .map(|transaction| transaction.transaction_id),
+            );
+            for transaction_id in &removed {
+                transactions.remove(transaction_id);
+            }
+        }
+
+        let mut evicted = Vec::new();
+        if let Some(max_age) = self.policy.max_age {
+            let expired: Vec<u32> = transactions
+                .values()
+                .filter(|transaction| {
+                    !transaction.under_dispute && is_older_than(transaction, max_age, now)
+                })
+                .map(|transaction| transaction.transaction_id)
+                .collect();
+            for transaction_id in expired {
+                evicted.extend(transactions.remove(&transaction_id));
+            }
+        }
+
+        if let Some(max_count) = self.policy.max_count {
+            while transactions.len() > max_count {
+                let oldest = transactions
+                    .values()
+                    .filter(|transaction| !transaction.under_dispute)
+                    .min_by_key(|transaction| transaction.sequence)
+                    .map(|transaction| transaction.transaction_id);
+                match oldest {
+                    Some(transaction_id) => evicted.extend(transactions.remove(&transaction_id)),
+                    // Everything left is under dispute
+                    None => break,
+                }
+            }
+        }
+
+        if let Some(spill) = &self.spill {
+            if !evicted.is_empty() {
+                spill.append(client_id, &evicted)?;
+            }
+        }
+        removed.extend(evicted.iter().map(|transaction| transaction.transaction_id));
+        Ok(removed)
+    }
+
+    // Looks for a previously evicted transaction, as long as it can still be disputed
+    pub(crate) fn find_spilled(
+        &self,
+        client_id: u16,
+        transaction_id: u32,
+        now: u64,
+    ) -> Result<Option<Transaction>, PaymentError> {
+        let spilled = match &self.spill {
+            Some(spill) => spill.find(client_id, transaction_id)?,
+            None => None,
+        };
+        Ok(spilled.filter(|transaction| self.disputable(transaction, now)))
+    }
+
+    // Transactions past the dispute window are only dropped from the history the next time
+    // their account is updated, so whatever the store returns is checked too
+    pub(crate) fn disputable(&self, transaction: &Transaction, now: u64) -> bool {
+        match self.policy.dispute_window {
+            Some(dispute_window) => !is_older_than(transaction, dispute_window, now),
+            None => true,
+        }
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn is_older_than(transaction: &Transaction, age: Duration, now: u64) -> bool {
+    now.saturating_sub(transaction.recorded_at) >= age.as_secs()
+}
+
+// Append-only file per client of evicted transactions. A transaction can be evicted more
+// than once if it was brought back for a dispute, the last record is the most recent one.
+struct Spill {
+    dir: PathBuf,
+}
+
+impl Spill {
+    fn path(&self, client_id: u16) -> PathBuf {
+        self.dir.join(format!("{}.spill", client_id))
+    }
+
+    // Only returns once the transactions have been flushed to disk, they are removed from
+    // the account's history right after
+    fn append(&self, client_id: u16, transactions: &[Transaction]) -> Result<(), PaymentError> {
+        let path = self.path(client_id);
+        let context = format!("Could not spill transactions to {}", path.display());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(PaymentError::storage_error(&context))?;
+        let mut writer = BufWriter::new(file);
+        for transaction in transactions {
+            write_transaction_record(transaction, &mut writer)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|file| file.sync_data())
+            .map_err(PaymentError::storage_error(context))
+    }
+
+    fn find(
+        &self,
+        client_id: u16,
+        transaction_id: u32,
+    ) -> Result<Option<Transaction>, PaymentError> {
+        let path = self.path(client_id);
+        let context = format!("Could not read spilled transactions {}", path.display());
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PaymentError::storage_error(context)(e)),
+        };
+
+        let mut found = None;
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(PaymentError::storage_error(&context))?;
+            let transaction = parse_transaction_record(&line)?;
+            if transaction.transaction_id == transaction_id {
+                found = Some(transaction);
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::payments::AccountService;
+    use std::str::FromStr;
+    use tempfile::tempdir;
+
+    async fn process(account_service: &AccountService, lines: &[&str]) {
+        for line in lines {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_count_without_spill() {
+        let account_service = AccountService::new();
+        account_service.set_retention(Retention::new(RetentionPolicy {
+            max_count: Some(2),
+            ..Default::default()
+        }));
+        process(
+            &account_service,
+            &[
+                "deposit,1,1,1.0",
+                "deposit,1,2,2.0",
+                "deposit,1,3,3.0",
+                "dispute,1,1,",
+            ],
+        )
+        .await;
+
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.transactions.len(), 2);
+        // The oldest deposit was evicted so it can no longer be disputed
+        assert_eq!(account.available(), 6.0);
+        assert_eq!(account.held(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_spilled_transactions_can_be_disputed() {
+        let dir = tempdir().unwrap();
+        let account_service = AccountService::new();
+        account_service.set_retention(
+            Retention::new(RetentionPolicy {
+                max_count: Some(1),
+                ..Default::default()
+            })
+            .with_spill(dir.path())
+            .unwrap(),
+        );
+        process(
+            &account_service,
+            &[
+                "deposit,1,1,1.0",
+                "deposit,1,2,2.0",
+                "deposit,1,3,3.0",
+                "dispute,1,1,",
+            ],
+        )
+        .await;
+
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 5.0);
+        assert_eq!(account.held(), 1.0);
+        assert!(account.transactions[&1].under_dispute);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_window() {
+        let dir = tempdir().unwrap();
+        let account_service = AccountService::new();
+        account_service.set_retention(
+            Retention::new(RetentionPolicy {
+                dispute_window: Some(Duration::ZERO),
+                ..Default::default()
+            })
+            .with_spill(dir.path())
+            .unwrap(),
+        );
+        process(&account_service, &["deposit,1,1,1.0", "dispute,1,1,"]).await;
+
+        let account = account_service.get_account(1).unwrap().unwrap();
+        assert!(account.transactions.is_empty());
+        assert_eq!(account.held(), 0.0);
+        assert!(!dir.path().join("1.spill").exists());
+    }
+}
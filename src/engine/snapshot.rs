@@ -0,0 +1,268 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::ledger;
+use crate::engine::payments::{Account, AccountService, Transaction, TransactionType};
+use crate::engine::upload::write_atomically;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+// Plain text encoding of the full account state, one record per line:
+//   account,<client>,<available>,<held>,<locked>
+//   transaction,<client>,<type>,<tx>,<amount>,<under_dispute>,<charged_back>,<sequence>,<recorded_at>
+// Transfers have the destination client as an extra tenth field. Every account is followed
+// by the records of its transactions. Balances are written as
+// decimals of their ledger units and amounts with `{}`, both round trip exactly.
+
+pub fn write_snapshot<W: Write>(accounts: &[Account], writer: &mut W) -> Result<(), PaymentError> {
+    for account in accounts {
+        write_account(account, writer)?;
+    }
+    Ok(())
+}
+
+// Writes the records of a single account and its transactions
+pub fn write_account<W: Write>(account: &Account, writer: &mut W) -> Result<(), PaymentError> {
+    write_account_record(account, writer)?;
+    for transaction in account.transactions.values() {
+        write_transaction_record(transaction, writer)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_account_record<W: Write>(
+    account: &Account,
+    writer: &mut W,
+) -> Result<(), PaymentError> {
+    writeln!(
+        writer,
+        "account,{},{},{},{}",
+        account.client_id,
+        ledger::from_units(account.available),
+        ledger::from_units(account.held),
+        account.locked
+    )
+    .map_err(PaymentError::storage_error(format!(
+        "Could not write account {}",
+        account.client_id
+    )))
+}
+
+pub(crate) fn write_transaction_record<W: Write>(
+    transaction: &Transaction,
+    writer: &mut W,
+) -> Result<(), PaymentError> {
+    let destination = transaction
+        .destination_id
+        .map(|destination_id| format!(",{}", destination_id))
+        .unwrap_or_default();
+    writeln!(
+        writer,
+        "transaction,{},{},{},{},{},{},{},{}{}",
+        transaction.client_id,
+        transaction.transaction_type,
+        transaction.transaction_id,
+        transaction.amount,
+        transaction.under_dispute,
+        transaction.charged_back,
+        transaction.sequence,
+        transaction.recorded_at,
+        destination
+    )
+    .map_err(PaymentError::storage_error(format!(
+        "Could not write transaction {}",
+        transaction.transaction_id
+    )))
+}
+
+pub(crate) fn parse_account_record(line: &str) -> Result<Account, PaymentError> {
+    let parts: Vec<&str> = line.split(',').collect();
+    if parts.len() != 5 || parts[0] != "account" {
+        return Err(invalid_record(line));
+    }
+    let client_id = parse_field::<u16>(parts[1], line)?;
+    let mut account = Account::new(&client_id);
+    account.available = ledger::decimal_to_units(parse_field(parts[2], line)?);
+    account.held = ledger::decimal_to_units(parse_field(parts[3], line)?);
+    account.locked = parse_field(parts[4], line)?;
+    Ok(account)
+}
+
+pub(crate) fn parse_transaction_record(line: &str) -> Result<Transaction, PaymentError> {
+    let parts: Vec<&str> = line.split(',').collect();
+    if !(parts.len() == 9 || parts.len() == 10) || parts[0] != "transaction" {
+        return Err(invalid_record(line));
+    }
+    Ok(Transaction {
+        client_id: parse_field(parts[1], line)?,
+        transaction_type: TransactionType::from_str(parts[2])?,
+        transaction_id: parse_field(parts[3], line)?,
+        amount: parse_field(parts[4], line)?,
+        destination_id: parts
+            .get(9)
+            .map(|destination_id| parse_field(destination_id, line))
+            .transpose()?,
+        under_dispute: parse_field(parts[5], line)?,
+        charged_back: parse_field(parts[6], line)?,
+        sequence: parse_field(parts[7], line)?,
+        recorded_at: parse_field(parts[8], line)?,
+    })
+}
+
+// Reads the accounts of a snapshot one at a time so the whole snapshot never has to be
+// in memory
+pub struct SnapshotReader<R: BufRead> {
+    lines: Lines<R>,
+    // Account record read while looking for the end of the previous account
+    next_account: Option<Account>,
+}
+
+impl<R: BufRead> SnapshotReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            next_account: None,
+        }
+    }
+
+    fn next_line(&mut self) -> Option<Result<String, PaymentError>> {
+        loop {
+            match self.lines.next()? {
+                Ok(line) if line.is_empty() => continue,
+                line => {
+                    return Some(
+                        line.map_err(PaymentError::storage_error("Could not read snapshot")),
+                    )
+                }
+            }
+        }
+    }
+
+    fn read_account(&mut self) -> Result<Option<Account>, PaymentError> {
+        let mut account = match self.next_account.take() {
+            Some(account) => account,
+            None => match self.next_line() {
+                Some(line) => parse_account_record(&line?)?,
+                None => return Ok(None),
+            },
+        };
+        while let Some(line) = self.next_line() {
+            let line = line?;
+            if line.starts_with("account,") {
+                self.next_account = Some(parse_account_record(&line)?);
+                break;
+            }
+            let transaction = parse_transaction_record(&line)?;
+            if transaction.client_id != account.client_id {
+                return Err(PaymentError::ParseError(format!(
+                    "Snapshot transaction outside its account: {}",
+                    line
+                )));
+            }
+            account
+                .transactions
+                .insert(transaction.transaction_id, transaction);
+        }
+        Ok(Some(account))
+    }
+}
+
+impl<R: BufRead> Iterator for SnapshotReader<R> {
+    type Item = Result<Account, PaymentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_account().transpose()
+    }
+}
+
+pub fn read_snapshot<R: BufRead>(reader: R) -> Result<Vec<Account>, PaymentError> {
+    SnapshotReader::new(reader).collect()
+}
+
+// Saves the full state of the account service, including stored transactions and
+// their dispute state, so it can be loaded back on the next run
+pub fn save_snapshot(account_service: &AccountService, path: &Path) -> Result<(), PaymentError> {
+    write_atomically(path, |writer| {
+        account_service.for_each_account(|account| write_account(account, writer))
+    })
+}
+
+// Replaces the state of the account service with the snapshot at `path`
+pub fn load_snapshot(account_service: &AccountService, path: &Path) -> Result<(), PaymentError> {
+    let file = File::open(path).map_err(PaymentError::storage_error(format!(
+        "Could not open snapshot {}",
+        path.display()
+    )))?;
+    account_service.restore(SnapshotReader::new(BufReader::new(file)))
+}
+
+fn parse_field<T: FromStr>(field: &str, line: &str) -> Result<T, PaymentError> {
+    field.parse::<T>().map_err(|_| invalid_record(line))
+}
+
+fn invalid_record(line: &str) -> PaymentError {
+    PaymentError::ParseError(format!("Invalid snapshot record: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let account_service = AccountService::new();
+        for line in [
+            "deposit,1,1,10.1234",
+            "deposit,2,2,5",
+            "dispute,2,2,",
+            "transfer,1,3,1,3",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let mut buffer = Vec::new();
+        write_snapshot(&account_service.accounts().unwrap(), &mut buffer).unwrap();
+        let restored = AccountService::new();
+        restored
+            .restore(SnapshotReader::new(buffer.as_slice()))
+            .unwrap();
+
+        let account = restored.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 9.1234);
+        assert_eq!(account.transactions[&3].destination_id(), Some(3));
+        assert_eq!(restored.get_account(2).unwrap().unwrap().held(), 5.0);
+
+        // The restored dispute can still be resolved
+        restored
+            .process_transaction(Transaction::from_str("resolve,2,2,").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(restored.get_account(2).unwrap().unwrap().available(), 5.0);
+        assert_eq!(restored.get_account(2).unwrap().unwrap().held(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.snapshot");
+        let account_service = AccountService::new();
+        account_service
+            .process_transaction(Transaction::from_str("deposit,1,1,3.0").unwrap())
+            .await
+            .unwrap();
+        save_snapshot(&account_service, &path).unwrap();
+
+        let loaded = AccountService::new();
+        loaded
+            .process_transaction(Transaction::from_str("deposit,9,9,1.0").unwrap())
+            .await
+            .unwrap();
+        load_snapshot(&loaded, &path).unwrap();
+
+        assert_eq!(loaded.get_account(1).unwrap().unwrap().total(), 3.0);
+        assert!(loaded.get_account(9).unwrap().is_none());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::journal;
+use crate::engine::payments::{Account, AccountService, TransactionOutcome, TransactionType};
+use crate::engine::policy::Policy;
+use crate::engine::retention::Retention;
+use crate::engine::snapshot::load_snapshot;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+// One line of a statement, the balances are the ones right after the transaction
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StatementEntry {
+    pub transaction_type: String,
+    pub transaction_id: u32,
+    // For disputes, resolves and chargebacks this is the amount of the referenced transaction
+    pub amount: f32,
+    // False if the transaction was ignored, e.g. a withdrawal without enough funds
+    pub applied: bool,
+    pub available: f32,
+    pub held: f32,
+    pub total: f32,
+    pub locked: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Statement {
+    pub client_id: u16,
+    pub entries: Vec<StatementEntry>,
+}
+
+impl Statement {
+    // The journal is the engine's ordered record of every transaction so the statement is
+    // built by replaying it. Transfers into the client, and their disputes, resolves and
+    // chargebacks, are listed along with the client's own transactions.
+    pub fn from_journal(journal_path: &Path, client_id: u16) -> Result<Self, PaymentError> {
+        Self::from_journal_with(
+            journal_path,
+            client_id,
+            Policy::default(),
+            Retention::default(),
+            None,
+        )
+    }
+
+    // The policy and retention must be the ones the journal was written with, see
+    // `Retention::read_only`, to get the same balances and accept or refuse disputes the same
+    // way. A journal started on top of saved state is replayed on top of that snapshot.
+    pub fn from_journal_with(
+        journal_path: &Path,
+        client_id: u16,
+        policy: Policy,
+        retention: Retention,
+        snapshot_path: Option<&Path>,
+    ) -> Result<Self, PaymentError> {
+        let account_service = AccountService::new();
+        account_service.set_policy(policy);
+        account_service.set_retention(retention);
+        // Keyed by source client and transaction id since transfers span two clients
+        let mut amounts: HashMap<(u16, u32), f32> = HashMap::new();
+        let mut incoming: HashSet<(u16, u32)> = HashSet::new();
+        if let Some(snapshot_path) = snapshot_path {
+            load_snapshot(&account_service, snapshot_path)?;
+        }
+        // Transactions from before the journal can still be disputed in it
+        account_service.for_each_account(|account| {
+            for transaction in account.transactions.values() {
+                let key = (transaction.client_id, transaction.transaction_id);
+                amounts.insert(key, transaction.amount);
+                if transaction.destination_id == Some(client_id) {
+                    incoming.insert(key);
+                }
+            }
+            Ok(())
+        })?;
+        let mut entries = Vec::new();
+
+        let reader = BufReader::new(File::open(journal_path).map_err(
+            PaymentError::storage_error(format!(
+                "Could not open journal {}",
+                journal_path.display()
+            )),
+        )?);
+        for line in reader.lines().skip(1) {
+            let line = line.map_err(PaymentError::storage_error(format!(
+                "Could not read journal {}",
+                journal_path.display()
+            )))?;
+            let (transaction, recorded_at) = journal::parse_record(&line)?;
+            let key = (transaction.client_id, transaction.transaction_id);
+            let amount = match transaction.transaction_type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    amounts.insert(key, transaction.amount);
+                    transaction.amount
+                }
+                TransactionType::Transfer => {
+                    amounts.insert(key, transaction.amount);
+                    if transaction.destination_id == Some(client_id) {
+                        incoming.insert(key);
+                    }
+                    transaction.amount
+                }
+                _ => amounts.get(&key).cloned().unwrap_or(0.0),
+            };
+            // Every transaction is applied so transfers find their source's balance
+            let listed = transaction.client_id == client_id || incoming.contains(&key);
+            let transaction_type = transaction.transaction_type.to_string();
+            let transaction_id = transaction.transaction_id;
+
+            let outcome = account_service.apply_at(transaction, recorded_at)?;
+            if !listed {
+                continue;
+            }
+            let after = account_service
+                .get_account(client_id)?
+                .unwrap_or_else(|| Account::new(&client_id));
+
+            entries.push(StatementEntry {
+                transaction_type,
+                transaction_id,
+                amount,
+                applied: outcome == TransactionOutcome::Applied,
+                available: after.available(),
+                held: after.held(),
+                total: after.total(),
+                locked: after.locked(),
+            });
+        }
+
+        Ok(Self { client_id, entries })
+    }
+
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "type,tx,amount,applied,available,held,total,locked")
+            .map_err(write_error)?;
+        for entry in &self.entries {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{}",
+                entry.transaction_type,
+                entry.transaction_id,
+                entry.amount,
+                entry.applied,
+                entry.available,
+                entry.held,
+                entry.total,
+                entry.locked
+            )
+            .map_err(write_error)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_json(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        write!(writer, "{{\"client\":{},\"transactions\":[", self.client_id)
+            .map_err(write_error)?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                write!(writer, ",").map_err(write_error)?;
+            }
+            write!(
+                writer,
+                "{{\"type\":\"{}\",\"tx\":{},\"amount\":{},\"applied\":{},\
+                 \"available\":{},\"held\":{},\"total\":{},\"locked\":{}}}",
+                entry.transaction_type,
+                entry.transaction_id,
+                entry.amount,
+                entry.applied,
+                entry.available,
+                entry.held,
+                entry.total,
+                entry.locked
+            )
+            .map_err(write_error)?;
+        }
+        writeln!(writer, "]}}").map_err(write_error)?;
+        Ok(())
+    }
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the statement")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::journal::Journal;
+    use crate::engine::payments::Transaction;
+    use crate::engine::retention::RetentionPolicy;
+    use crate::engine::snapshot::save_snapshot;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_statement_from_journal() {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("journal.csv");
+        let account_service = AccountService::new();
+        account_service.set_journal(Journal::open(&journal_path).unwrap());
+        for line in [
+            "deposit,1,1,10.0",
+            "deposit,2,2,3.0",
+            "withdrawal,1,3,20.0",
+            "withdrawal,1,4,4.0",
+            "dispute,1,1,",
+            "chargeback,1,1,",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let statement = Statement::from_journal(&journal_path, 1).unwrap();
+        let mut csv = Vec::new();
+        statement.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,tx,amount,applied,available,held,total,locked\n\
+             deposit,1,10,true,10,0,10,false\n\
+             withdrawal,3,20,false,10,0,10,false\n\
+             withdrawal,4,4,true,6,0,6,false\n\
+             dispute,1,10,true,-4,10,6,false\n\
+             chargeback,1,10,true,-4,0,-4,true\n"
+        );
+
+        let mut json = Vec::new();
+        Statement::from_journal(&journal_path, 2)
+            .unwrap()
+            .write_json(&mut json)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"client\":2,\"transactions\":[{\"type\":\"deposit\",\"tx\":2,\"amount\":3,\
+             \"applied\":true,\"available\":3,\"held\":0,\"total\":3,\"locked\":false}]}\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statement_from_snapshot() {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("journal.csv");
+        let snapshot_path = dir.path().join("accounts.snapshot");
+        let account_service = AccountService::new();
+        account_service
+            .process_transaction(Transaction::from_str("deposit,1,1,10.0").unwrap())
+            .await
+            .unwrap();
+        save_snapshot(&account_service, &snapshot_path).unwrap();
+
+        // The journal only starts after the saved state
+        account_service.set_journal(Journal::open(&journal_path).unwrap());
+        for line in ["withdrawal,1,2,4.0", "dispute,1,1,"] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let mut csv = Vec::new();
+        Statement::from_journal_with(
+            &journal_path,
+            1,
+            Policy::default(),
+            Retention::default(),
+            Some(&snapshot_path),
+        )
+        .unwrap()
+        .write_csv(&mut csv)
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,tx,amount,applied,available,held,total,locked\n\
+             withdrawal,2,4,true,6,0,6,false\n\
+             dispute,1,10,true,-4,10,6,false\n"
+        );
+    }
+
+    #[test]
+    fn test_statement_with_retention() {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("journal.csv");
+        std::fs::write(
+            &journal_path,
+            "type,client,tx,amount,recorded_at\n\
+             deposit,1,1,10.0,100\n\
+             deposit,1,2,5.0,100\n\
+             dispute,1,1,,200\n",
+        )
+        .unwrap();
+
+        // The run evicted the first deposit without spilling it so the dispute was refused
+        let retention = Retention::new(RetentionPolicy {
+            max_count: Some(1),
+            ..Default::default()
+        });
+        let statement =
+            Statement::from_journal_with(&journal_path, 1, Policy::default(), retention, None)
+                .unwrap();
+        assert!(!statement.entries[2].applied);
+        assert_eq!(statement.entries[2].held, 0.0);
+
+        // It was spilled so the dispute went through, nothing is written to the spill
+        // directory when building the statement
+        let spill = dir.path().join("spill");
+        let retention = Retention::new(RetentionPolicy {
+            max_count: Some(1),
+            dispute_window: Some(Duration::from_secs(1000)),
+            ..Default::default()
+        })
+        .with_spill(&spill)
+        .unwrap();
+        let statement = Statement::from_journal_with(
+            &journal_path,
+            1,
+            Policy::default(),
+            retention.read_only(),
+            None,
+        )
+        .unwrap();
+        assert!(statement.entries[2].applied);
+        assert_eq!(statement.entries[2].held, 10.0);
+        assert_eq!(std::fs::read_dir(&spill).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_statement_with_transfers() {
+        let dir = tempdir().unwrap();
+        let journal_path = dir.path().join("journal.csv");
+        let account_service = AccountService::new();
+        account_service.set_journal(Journal::open(&journal_path).unwrap());
+        for line in [
+            "deposit,1,1,10.0",
+            "deposit,2,2,1.0",
+            "transfer,1,3,4.0,2",
+            "dispute,1,3,",
+            "resolve,1,3,",
+        ] {
+            account_service
+                .apply(Transaction::from_str(line).unwrap())
+                .unwrap();
+        }
+
+        let mut csv = Vec::new();
+        Statement::from_journal(&journal_path, 2)
+            .unwrap()
+            .write_csv(&mut csv)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,tx,amount,applied,available,held,total,locked\n\
+             deposit,2,1,true,1,0,1,false\n\
+             transfer,3,4,true,5,0,5,false\n\
+             dispute,3,4,true,1,4,5,false\n\
+             resolve,3,4,true,5,0,5,false\n"
+        );
+    }
+}
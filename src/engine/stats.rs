@@ -0,0 +1,247 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::ledger::{from_units, Posting};
+use crate::engine::payments::{RejectionReason, TransactionOutcome, TransactionType};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// Totals for a run, gathered while the rows are read and the transactions processed.
+// Amounts are kept in the same units as the ledger so the sums are exact.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RunStats {
+    pub rows_read: u64,
+    // Rows that couldn't be parsed into a transaction
+    pub parse_failures: u64,
+    // Processed transactions per type, applied or not
+    pub transactions: BTreeMap<String, u64>,
+    pub applied: u64,
+    pub rejected: BTreeMap<RejectionReason, u64>,
+    pub locked_accounts: u64,
+    deposited: i64,
+    withdrawn: i64,
+    transferred: i64,
+    disputed: i64,
+    resolved: i64,
+    charged_back: i64,
+    // Only meaningful within the process that recorded them
+    #[cfg_attr(feature = "serde", serde(skip))]
+    started: Option<Instant>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    finished: Option<Instant>,
+}
+
+impl RunStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        transaction_type: &TransactionType,
+        outcome: &TransactionOutcome,
+        postings: &[Posting],
+    ) {
+        self.touch();
+        *self
+            .transactions
+            .entry(transaction_type.to_string())
+            .or_insert(0) += 1;
+        match outcome {
+            TransactionOutcome::Applied => self.applied += 1,
+            TransactionOutcome::Rejected(reason) => *self.rejected.entry(*reason).or_insert(0) += 1,
+        }
+
+        // The first posting of every group carries the full amount
+        let amount = postings
+            .first()
+            .map(|(_, amount)| amount.abs())
+            .unwrap_or(0);
+        match transaction_type {
+            TransactionType::Deposit => self.deposited += amount,
+            TransactionType::Withdrawal => self.withdrawn += amount,
+            TransactionType::Transfer => self.transferred += amount,
+            TransactionType::Dispute => self.disputed += amount,
+            TransactionType::Resolve => self.resolved += amount,
+            TransactionType::Chargeback => self.charged_back += amount,
+        }
+    }
+
+    pub fn deposited(&self) -> f64 {
+        from_units(self.deposited)
+    }
+
+    pub fn withdrawn(&self) -> f64 {
+        from_units(self.withdrawn)
+    }
+
+    // Moved between clients, the total held by all accounts doesn't change
+    pub fn transferred(&self) -> f64 {
+        from_units(self.transferred)
+    }
+
+    // Moved into held by disputes
+    pub fn disputed(&self) -> f64 {
+        from_units(self.disputed)
+    }
+
+    // Released from held by resolves
+    pub fn resolved(&self) -> f64 {
+        from_units(self.resolved)
+    }
+
+    pub fn charged_back(&self) -> f64 {
+        from_units(self.charged_back)
+    }
+
+    // Time from the first row read to the last transaction processed
+    pub fn elapsed(&self) -> Duration {
+        match (self.started, self.finished) {
+            (Some(started), Some(finished)) => finished.duration_since(started),
+            _ => Duration::ZERO,
+        }
+    }
+
+    // Rows per second
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed().as_secs_f64();
+        if seconds > 0.0 {
+            self.rows_read as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    pub(crate) fn touch(&mut self) {
+        let now = Instant::now();
+        self.started.get_or_insert(now);
+        self.finished = Some(now);
+    }
+
+    // Human readable summary, one value per line
+    pub fn write_text(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        writeln!(writer, "rows read: {}", self.rows_read).map_err(write_error)?;
+        writeln!(writer, "parse failures: {}", self.parse_failures).map_err(write_error)?;
+        for (transaction_type, count) in &self.transactions {
+            writeln!(writer, "{}: {}", transaction_type, count).map_err(write_error)?;
+        }
+        writeln!(writer, "applied: {}", self.applied).map_err(write_error)?;
+        for (reason, count) in &self.rejected {
+            writeln!(writer, "rejected {}: {}", reason, count).map_err(write_error)?;
+        }
+        writeln!(writer, "deposited: {}", self.deposited()).map_err(write_error)?;
+        writeln!(writer, "withdrawn: {}", self.withdrawn()).map_err(write_error)?;
+        writeln!(writer, "transferred: {}", self.transferred()).map_err(write_error)?;
+        writeln!(writer, "disputed: {}", self.disputed()).map_err(write_error)?;
+        writeln!(writer, "resolved: {}", self.resolved()).map_err(write_error)?;
+        writeln!(writer, "charged back: {}", self.charged_back()).map_err(write_error)?;
+        writeln!(writer, "locked accounts: {}", self.locked_accounts).map_err(write_error)?;
+        writeln!(
+            writer,
+            "processing time: {:.3}s",
+            self.elapsed().as_secs_f64()
+        )
+        .map_err(write_error)?;
+        writeln!(writer, "throughput: {:.0} rows/s", self.throughput()).map_err(write_error)?;
+        Ok(())
+    }
+
+    pub fn write_json(&self, writer: &mut dyn Write) -> Result<(), PaymentError> {
+        let transactions: Vec<String> = self
+            .transactions
+            .iter()
+            .map(|(transaction_type, count)| format!("\"{}\":{}", transaction_type, count))
+            .collect();
+        let rejected: Vec<String> = self
+            .rejected
+            .iter()
+            .map(|(reason, count)| format!("\"{}\":{}", reason, count))
+            .collect();
+        writeln!(
+            writer,
+            "{{\"rows_read\":{},\"parse_failures\":{},\"transactions\":{{{}}},\"applied\":{},\
+             \"rejected\":{{{}}},\"deposited\":{},\"withdrawn\":{},\"transferred\":{},\
+             \"disputed\":{},\"resolved\":{},\"charged_back\":{},\"locked_accounts\":{},\
+             \"processing_time_secs\":{},\"throughput_rows_per_sec\":{}}}",
+            self.rows_read,
+            self.parse_failures,
+            transactions.join(","),
+            self.applied,
+            rejected.join(","),
+            self.deposited(),
+            self.withdrawn(),
+            self.transferred(),
+            self.disputed(),
+            self.resolved(),
+            self.charged_back(),
+            self.locked_accounts,
+            self.elapsed().as_secs_f64(),
+            self.throughput()
+        )
+        .map_err(write_error)?;
+        Ok(())
+    }
+}
+
+fn write_error(error: io::Error) -> PaymentError {
+    PaymentError::storage_error("Could not write the run stats")(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::payments::{AccountService, RejectionReason, Transaction};
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_run_stats() {
+        let account_service = AccountService::new();
+        for line in [
+            "deposit,1,1,10.0",
+            "deposit,2,2,0.1",
+            "deposit,2,3,0.2",
+            "withdrawal,1,4,20.0",
+            "withdrawal,1,5,2.5",
+            "dispute,2,2,",
+            "dispute,2,2,",
+            "chargeback,2,2,",
+            "deposit,2,6,1.0",
+            "resolve,1,9,",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let stats = account_service.run_stats().unwrap();
+        assert_eq!(stats.transactions["deposit"], 4);
+        assert_eq!(stats.transactions["dispute"], 2);
+        assert_eq!(stats.applied, 6);
+        assert_eq!(stats.rejected[&RejectionReason::InsufficientFunds], 1);
+        assert_eq!(stats.rejected[&RejectionReason::AlreadyDisputed], 1);
+        assert_eq!(stats.rejected[&RejectionReason::AccountLocked], 1);
+        assert_eq!(stats.rejected[&RejectionReason::UnknownTransaction], 1);
+        assert_eq!(stats.deposited(), 10.3);
+        assert_eq!(stats.withdrawn(), 2.5);
+        assert_eq!(stats.disputed(), 0.1);
+        assert_eq!(stats.charged_back(), 0.1);
+        assert_eq!(stats.locked_accounts, 1);
+
+        let mut json = Vec::new();
+        stats.write_json(&mut json).unwrap();
+        assert!(String::from_utf8(json).unwrap().starts_with(
+            "{\"rows_read\":0,\"parse_failures\":0,\
+             \"transactions\":{\"chargeback\":1,\"deposit\":4,\"dispute\":2,\"resolve\":1,\"withdrawal\":2},\
+             \"applied\":6,\"rejected\":{\"account_locked\":1,\"insufficient_funds\":1,\
+             \"unknown_transaction\":1,\"already_disputed\":1},\"deposited\":10.3,"
+        ));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&stats).unwrap();
+            let deserialized: super::RunStats = serde_json::from_str(&json).unwrap();
+            assert_eq!(deserialized.rejected, stats.rejected);
+            assert_eq!(deserialized.deposited(), 10.3);
+        }
+    }
+}
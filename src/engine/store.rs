@@ -0,0 +1,630 @@
+use crate::engine::errors::PaymentError;
+use crate::engine::payments::{Account, Transaction};
+use crate::engine::snapshot::{
+    parse_account_record, parse_transaction_record, read_snapshot, write_account_record,
+    write_snapshot, write_transaction_record,
+};
+use crate::engine::upload::write_atomically;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+// Storage for the accounts behind `AccountService`. The account service serializes all
+// access so implementations don't have to deal with concurrent callers themselves.
+pub trait AccountStore: Send {
+    // The account along with its whole transaction history
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>, PaymentError>;
+
+    // The account without its transactions, which is all processing needs. Stored
+    // transactions are looked up one at a time with `get_transaction`.
+    fn get_balances(&self, client_id: u16) -> Result<Option<Account>, PaymentError>;
+
+    fn create_account(&mut self, client_id: u16) -> Result<Account, PaymentError>;
+
+    // Saves accounts read with `get_balances` along with the transactions that were added
+    // or changed since, the rest of their history is kept. Either every account is saved
+    // or none is.
+    fn update_balances(&mut self, accounts: Vec<Account>) -> Result<(), PaymentError>;
+
+    // Replaces an account along with its whole history, e.g. when restoring saved state
+    fn update_account(&mut self, account: Account) -> Result<(), PaymentError>;
+
+    // Looks up a previously stored deposit or withdrawal, e.g. for a dispute
+    fn get_transaction(
+        &self,
+        client_id: u16,
+        transaction_id: u32,
+    ) -> Result<Option<Transaction>, PaymentError>;
+
+    // The whole stored history of an account, only read when the retention policy could
+    // evict some of it
+    fn transactions(&self, client_id: u16) -> Result<HashMap<u32, Transaction>, PaymentError>;
+
+    // Drops transactions from the history of an account, e.g. once they are evicted
+    fn remove_transactions(
+        &mut self,
+        client_id: u16,
+        transaction_ids: &[u32],
+    ) -> Result<(), PaymentError>;
+
+    // Up to `limit` accounts with a client id above `after`, in client id order, so every
+    // account can be read without holding all of them in memory
+    fn accounts_after(
+        &self,
+        after: Option<u16>,
+        limit: usize,
+    ) -> Result<Vec<Account>, PaymentError>;
+
+    // Removes every account
+    fn clear(&mut self) -> Result<(), PaymentError>;
+}
+
+#[derive(Default)]
+pub struct InMemoryStore {
+    // Map of client id to account, ordered so accounts can be read a page at a time
+    accounts: BTreeMap<u16, Account>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        Self {
+            accounts: BTreeMap::new(),
+        }
+    }
+}
+
+impl AccountStore for InMemoryStore {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>, PaymentError> {
+        Ok(self.accounts.get(&client_id).cloned())
+    }
+
+    fn get_balances(&self, client_id: u16) -> Result<Option<Account>, PaymentError> {
+        Ok(self.accounts.get(&client_id).map(Account::balances))
+    }
+
+    fn create_account(&mut self, client_id: u16) -> Result<Account, PaymentError> {
+        let account = Account::new(&client_id);
+        self.accounts.insert(client_id, account.clone());
+        Ok(account)
+    }
+
+    fn update_balances(&mut self, accounts: Vec<Account>) -> Result<(), PaymentError> {
+        for account in accounts {
+            let stored = self
+                .accounts
+                .entry(account.client_id)
+                .or_insert_with(|| Account::new(&account.client_id));
+            stored.available = account.available;
+            stored.held = account.held;
+            stored.locked = account.locked;
+            stored.transactions.extend(account.transactions);
+        }
+        Ok(())
+    }
+
+    fn update_account(&mut self, account: Account) -> Result<(), PaymentError> {
+        self.accounts.insert(account.client_id, account);
+        Ok(())
+    }
+
+    fn get_transaction(
+        &self,
+        client_id: u16,
+        transaction_id: u32,
+    ) -> Result<Option<Transaction>, PaymentError> {
+        Ok(self
+            .accounts
+            .get(&client_id)
+            .and_then(|account| account.transactions.get(&transaction_id))
+            .cloned())
+    }
+
+    fn transactions(&self, client_id: u16) -> Result<HashMap<u32, Transaction>, PaymentError> {
+        Ok(self
+            .accounts
+            .get(&client_id)
+            .map(|account| account.transactions.clone())
+            .unwrap_or_default())
+    }
+
+    fn remove_transactions(
+        &mut self,
+        client_id: u16,
+        transaction_ids: &[u32],
+    ) -> Result<(), PaymentError> {
+        if let Some(account) = self.accounts.get_mut(&client_id) {
+            for transaction_id in transaction_ids {
+                account.transactions.remove(transaction_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn accounts_after(
+        &self,
+        after: Option<u16>,
+        limit: usize,
+    ) -> Result<Vec<Account>, PaymentError> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        Ok(self
+            .accounts
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, account)| account.clone())
+            .collect())
+    }
+
+    fn clear(&mut self) -> Result<(), PaymentError> {
+        self.accounts.clear();
+        Ok(())
+    }
+}
+
+// Written in full before an update is applied to the account files
+const PENDING_UPDATE: &str = "update.pending";
+// Same for the transactions removed from an account's history, as `<client>,<tx>,<tx>...`
+const PENDING_REMOVAL: &str = "remove.pending";
+
+// Embedded key-value store keyed by client id so only the accounts being worked on have to
+// be in memory. The balances of every account are kept in a small file under `dir` that is
+// replaced on every change, its transactions in an append-only log next to it where the
+// last record of a transaction is its current state. The balances file also records how
+// much of the log is committed, anything after that is left over from an interrupted update.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn open(dir: &Path) -> Result<Self, PaymentError> {
+        fs::create_dir_all(dir).map_err(PaymentError::storage_error(format!(
+            "Could not create account store {}",
+            dir.display()
+        )))?;
+        let store = Self {
+            dir: dir.to_path_buf(),
+        };
+        store.finish_pending_update()?;
+        Ok(store)
+    }
+
+    fn account_path(&self, client_id: u16) -> PathBuf {
+        self.dir.join(format!("{}.account", client_id))
+    }
+
+    fn transactions_path(&self, client_id: u16) -> PathBuf {
+        self.dir.join(format!("{}.transactions", client_id))
+    }
+
+    // The balances of the account and the committed length of its transaction log
+    fn read_balances(&self, client_id: u16) -> Result<Option<(Account, u64)>, PaymentError> {
+        let path = self.account_path(client_id);
+        let context = format!("Could not read account {}", path.display());
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PaymentError::storage_error(context)(e)),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let mut next_line = || {
+            lines
+                .next()
+                .transpose()
+                .map(Option::unwrap_or_default)
+                .map_err(PaymentError::storage_error(&context))
+        };
+        let account = parse_account_record(&next_line()?)?;
+        let line = next_line()?;
+        let log_length = line
+            .strip_prefix("transactions,")
+            .and_then(|length| length.parse::<u64>().ok())
+            .ok_or_else(|| {
+                PaymentError::ParseError(format!("Invalid account store record: {}", line))
+            })?;
+        Ok(Some((account, log_length)))
+    }
+
+    fn write_balances(&self, account: &Account, log_length: u64) -> Result<(), PaymentError> {
+        write_atomically(&self.account_path(account.client_id), |writer| {
+            write_account_record(account, writer)?;
+            writeln!(writer, "transactions,{}", log_length).map_err(PaymentError::storage_error(
+                format!("Could not write account {}", account.client_id),
+            ))
+        })
+    }
+
+    fn read_transactions(
+        &self,
+        client_id: u16,
+        log_length: u64,
+    ) -> Result<HashMap<u32, Transaction>, PaymentError> {
+        let mut transactions = HashMap::new();
+        if log_length == 0 {
+            return Ok(transactions);
+        }
+        let path = self.transactions_path(client_id);
+        let context = format!("Could not read transactions {}", path.display());
+        let file = File::open(&path).map_err(PaymentError::storage_error(&context))?;
+        for line in BufReader::new(file.take(log_length)).lines() {
+            let line = line.map_err(PaymentError::storage_error(&context))?;
+            let transaction = parse_transaction_record(&line)?;
+            transactions.insert(transaction.transaction_id, transaction);
+        }
+        Ok(transactions)
+    }
+
+    // Every change is written to a pending file first, which is the point it is committed
+    // at, so a change that was interrupted while the account files were being written can
+    // be applied again the next time the store is opened
+    fn finish_pending_update(&self) -> Result<(), PaymentError> {
+        let path = self.dir.join(PENDING_UPDATE);
+        match File::open(&path) {
+            Ok(file) => {
+                self.apply_update(read_snapshot(BufReader::new(file))?)?;
+                remove_pending(&path)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(PaymentError::storage_error(format!(
+                    "Could not read pending update {}",
+                    path.display()
+                ))(e))
+            }
+        }
+
+        let path = self.dir.join(PENDING_REMOVAL);
+        match fs::read_to_string(&path) {
+            Ok(removal) => {
+                let (client_id, transaction_ids) =
+                    parse_removal(removal.trim_end()).ok_or_else(|| {
+                        PaymentError::ParseError(format!(
+                            "Invalid pending removal: {}",
+                            removal.trim_end()
+                        ))
+                    })?;
+                self.apply_removal(client_id, &transaction_ids)?;
+                remove_pending(&path)?;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(PaymentError::storage_error(format!(
+                    "Could not read pending removal {}",
+                    path.display()
+                ))(e))
+            }
+        }
+        Ok(())
+    }
+
+    // Safe to apply more than once, the log is cut back to its committed length before the
+    // new records are appended
+    fn apply_update(&self, accounts: Vec<Account>) -> Result<(), PaymentError> {
+        for account in accounts {
+            let mut log_length = match self.read_balances(account.client_id)? {
+                Some((_, log_length)) => log_length,
+                None => 0,
+            };
+            if !account.transactions.is_empty() {
+                let mut records = Vec::new();
+                for transaction in account.transactions.values() {
+                    write_transaction_record(transaction, &mut records)?;
+                }
+                let path = self.transactions_path(account.client_id);
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(false)
+                    .open(&path)
+                    .and_then(|mut file| {
+                        file.set_len(log_length)?;
+                        file.seek(SeekFrom::End(0))?;
+                        file.write_all(&records)?;
+                        file.sync_data()
+                    })
+                    .map_err(PaymentError::storage_error(format!(
+                        "Could not append to transactions {}",
+                        path.display()
+                    )))?;
+                log_length += records.len() as u64;
+            }
+            self.write_balances(&account, log_length)?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the log without the removed transactions. Safe to apply more than once, if
+    // the balances weren't written yet their recorded length still covers the whole new log.
+    fn apply_removal(&self, client_id: u16, transaction_ids: &[u32]) -> Result<(), PaymentError> {
+        let (account, log_length) = match self.read_balances(client_id)? {
+            Some(balances) => balances,
+            None => return Ok(()),
+        };
+        let mut transactions = self.read_transactions(client_id, log_length)?;
+        for transaction_id in transaction_ids {
+            transactions.remove(transaction_id);
+        }
+
+        let path = self.transactions_path(client_id);
+        write_atomically(&path, |writer| {
+            for transaction in transactions.values() {
+                write_transaction_record(transaction, writer)?;
+            }
+            Ok(())
+        })?;
+        self.write_balances(&account, log_length_of(&path)?)
+    }
+
+    fn client_ids(&self) -> Result<Vec<u16>, PaymentError> {
+        let mut client_ids = Vec::new();
+        let context = format!("Could not list account store {}", self.dir.display());
+        let entries = fs::read_dir(&self.dir).map_err(PaymentError::storage_error(&context))?;
+        for entry in entries {
+            let path = entry.map_err(PaymentError::storage_error(&context))?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "account")
+            {
+                client_ids.extend(
+                    path.file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| stem.parse::<u16>().ok()),
+                );
+            }
+        }
+        Ok(client_ids)
+    }
+}
+
+impl AccountStore for FileStore {
+    fn get_account(&self, client_id: u16) -> Result<Option<Account>, PaymentError> {
+        let (mut account, log_length) = match self.read_balances(client_id)? {
+            Some(balances) => balances,
+            None => return Ok(None),
+        };
+        account.transactions = self.read_transactions(client_id, log_length)?;
+        Ok(Some(account))
+    }
+
+    fn get_balances(&self, client_id: u16) -> Result<Option<Account>, PaymentError> {
+        Ok(self.read_balances(client_id)?.map(|(account, _)| account))
+    }
+
+    // Nothing is written until the account is updated
+    fn create_account(&mut self, client_id: u16) -> Result<Account, PaymentError> {
+        Ok(Account::new(&client_id))
+    }
+
+    fn update_balances(&mut self, accounts: Vec<Account>) -> Result<(), PaymentError> {
+        let path = self.dir.join(PENDING_UPDATE);
+        write_atomically(&path, |writer| write_snapshot(&accounts, writer))?;
+        self.apply_update(accounts)?;
+        remove_pending(&path)
+    }
+
+    fn update_account(&mut self, account: Account) -> Result<(), PaymentError> {
+        // The account is gone until both files are written, its old balances would record
+        // the wrong length for the new log
+        remove_if_exists(&self.account_path(account.client_id))?;
+        let path = self.transactions_path(account.client_id);
+        write_atomically(&path, |writer| {
+            for transaction in account.transactions.values() {
+                write_transaction_record(transaction, writer)?;
+            }
+            Ok(())
+        })?;
+        self.write_balances(&account, log_length_of(&path)?)
+    }
+
+    fn get_transaction(
+        &self,
+        client_id: u16,
+        transaction_id: u32,
+    ) -> Result<Option<Transaction>, PaymentError> {
+        Ok(self.transactions(client_id)?.remove(&transaction_id))
+    }
+
+    fn transactions(&self, client_id: u16) -> Result<HashMap<u32, Transaction>, PaymentError> {
+        match self.read_balances(client_id)? {
+            Some((_, log_length)) => self.read_transactions(client_id, log_length),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn remove_transactions(
+        &mut self,
+        client_id: u16,
+        transaction_ids: &[u32],
+    ) -> Result<(), PaymentError> {
+        let path = self.dir.join(PENDING_REMOVAL);
+        write_atomically(&path, |writer| {
+            let transaction_ids: Vec<String> = transaction_ids.iter().map(u32::to_string).collect();
+            writeln!(writer, "{},{}", client_id, transaction_ids.join(",")).map_err(
+                PaymentError::storage_error(format!(
+                    "Could not write pending removal for client {}",
+                    client_id
+                )),
+            )
+        })?;
+        self.apply_removal(client_id, transaction_ids)?;
+        remove_pending(&path)
+    }
+
+    // Only the files of the accounts on the page are read
+    fn accounts_after(
+        &self,
+        after: Option<u16>,
+        limit: usize,
+    ) -> Result<Vec<Account>, PaymentError> {
+        let mut client_ids: Vec<u16> = self
+            .client_ids()?
+            .into_iter()
+            .filter(|client_id| after.is_none_or(|after| *client_id > after))
+            .collect();
+        client_ids.sort_unstable();
+
+        let mut accounts = Vec::new();
+        for client_id in client_ids.into_iter().take(limit) {
+            accounts.extend(self.get_account(client_id)?);
+        }
+        Ok(accounts)
+    }
+
+    fn clear(&mut self) -> Result<(), PaymentError> {
+        for client_id in self.client_ids()? {
+            remove_if_exists(&self.account_path(client_id))?;
+            remove_if_exists(&self.transactions_path(client_id))?;
+        }
+        Ok(())
+    }
+}
+
+// Removing the pending file is what marks the change as done
+fn remove_pending(path: &Path) -> Result<(), PaymentError> {
+    fs::remove_file(path).map_err(PaymentError::storage_error(format!(
+        "Could not remove {}",
+        path.display()
+    )))
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), PaymentError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(PaymentError::storage_error(format!(
+            "Could not remove {}",
+            path.display()
+        ))(e)),
+        _ => Ok(()),
+    }
+}
+
+// Length of a transaction log that was just written in full
+fn log_length_of(path: &Path) -> Result<u64, PaymentError> {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .map_err(PaymentError::storage_error(format!(
+            "Could not read the length of {}",
+            path.display()
+        )))
+}
+
+fn parse_removal(removal: &str) -> Option<(u16, Vec<u32>)> {
+    let mut fields = removal.split(',');
+    let client_id = fields.next()?.parse().ok()?;
+    let transaction_ids = fields
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    Some((client_id, transaction_ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ledger;
+    use crate::engine::payments::AccountService;
+    use crate::engine::retention::{Retention, RetentionPolicy};
+    use std::str::FromStr;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = tempdir().unwrap();
+        let account_service =
+            AccountService::with_store(Box::new(FileStore::open(dir.path()).unwrap()));
+        for line in [
+            "deposit,1,1,10.0",
+            "deposit,2,2,5.0",
+            "dispute,1,1,",
+            "transfer,2,3,2.0,1",
+        ] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        // A fresh store over the same directory sees everything that was written
+        let store = FileStore::open(dir.path()).unwrap();
+        assert_eq!(store.accounts_after(None, 10).unwrap().len(), 2);
+        assert_eq!(store.get_account(1).unwrap().unwrap().held(), 10.0);
+        assert!(store.get_transaction(1, 1).unwrap().unwrap().under_dispute);
+        // Both sides of the transfer were written
+        assert_eq!(store.get_account(1).unwrap().unwrap().available(), 2.0);
+        assert_eq!(store.get_account(2).unwrap().unwrap().total(), 3.0);
+        assert_eq!(
+            store
+                .get_transaction(2, 3)
+                .unwrap()
+                .unwrap()
+                .destination_id(),
+            Some(1)
+        );
+        assert!(store.get_account(3).unwrap().is_none());
+        let page = store.accounts_after(Some(1), 10).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].client_id, 2);
+    }
+
+    #[test]
+    fn test_file_store_finishes_interrupted_update() {
+        let dir = tempdir().unwrap();
+        let mut store = FileStore::open(dir.path()).unwrap();
+        let mut account = Account::new(&1);
+        account.available = ledger::to_units(10.0);
+        account
+            .transactions
+            .insert(1, Transaction::from_str("deposit,1,1,10.0").unwrap());
+        store.update_balances(vec![account.clone()]).unwrap();
+
+        // An update that was committed but only partly applied, half a record made it into
+        // the log and the balances were never written
+        let mut update = account.balances();
+        update.available = ledger::to_units(15.0);
+        update
+            .transactions
+            .insert(2, Transaction::from_str("deposit,1,2,5.0").unwrap());
+        write_atomically(&dir.path().join(PENDING_UPDATE), |writer| {
+            write_snapshot(&[update], writer)
+        })
+        .unwrap();
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("1.transactions"))
+            .unwrap();
+        log.write_all(b"transaction,1,dep").unwrap();
+
+        let store = FileStore::open(dir.path()).unwrap();
+        let account = store.get_account(1).unwrap().unwrap();
+        assert_eq!(account.available(), 15.0);
+        assert_eq!(account.transactions.len(), 2);
+        assert!(!dir.path().join(PENDING_UPDATE).exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_store_retention() {
+        let dir = tempdir().unwrap();
+        let account_service =
+            AccountService::with_store(Box::new(FileStore::open(dir.path()).unwrap()));
+        account_service.set_retention(Retention::new(RetentionPolicy {
+            max_count: Some(1),
+            ..Default::default()
+        }));
+        for line in ["deposit,1,1,1.0", "deposit,1,2,2.0", "deposit,1,3,3.0"] {
+            account_service
+                .process_transaction(Transaction::from_str(line).unwrap())
+                .await
+                .unwrap();
+        }
+
+        // The evicted transactions are gone from the log too
+        let store = FileStore::open(dir.path()).unwrap();
+        let account = store.get_account(1).unwrap().unwrap();
+        assert_eq!(account.total(), 6.0);
+        assert_eq!(account.transactions.len(), 1);
+        assert!(account.transactions.contains_key(&3));
+        let log = fs::read_to_string(dir.path().join("1.transactions")).unwrap();
+        assert_eq!(log.lines().count(), 1);
+    }
+}
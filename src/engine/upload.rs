@@ -0,0 +1,105 @@
+use crate::engine::download::{s3_not_implemented, LocalFile, S3File, UriSchemes};
+use crate::engine::errors::PaymentError;
+use async_trait::async_trait;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// Counterpart of `Downloadable` for writing results out. Consumers must never be able
+// to observe a partially uploaded file.
+#[async_trait]
+pub trait Uploadable {
+    async fn upload(&self, contents: &[u8]) -> Result<(), PaymentError>;
+}
+
+#[async_trait]
+impl Uploadable for LocalFile {
+    async fn upload(&self, contents: &[u8]) -> Result<(), PaymentError> {
+        write_atomically(Path::new(&self.file_path), |writer| {
+            writer
+                .write_all(contents)
+                .map_err(PaymentError::storage_error(format!(
+                    "Could not write {}",
+                    self.file_path
+                )))
+        })
+    }
+}
+
+#[async_trait]
+impl Uploadable for S3File {
+    async fn upload(&self, _contents: &[u8]) -> Result<(), PaymentError> {
+        Err(s3_not_implemented("uploads"))
+    }
+}
+
+pub fn uploadable_for_uri(uri: &str) -> Result<Box<dyn Uploadable>, PaymentError> {
+    let uri_parts: Vec<&str> = uri.split("://").collect();
+    if uri_parts.len() != 2 {
+        return Err(PaymentError::SourceError {
+            context: format!("Invalid uri: {}", uri),
+            source: None,
+        });
+    }
+    let scheme = UriSchemes::from_str(uri_parts[0])?;
+    let path = uri_parts[1];
+
+    let uploadable: Box<dyn Uploadable> = match scheme {
+        UriSchemes::File => Box::new(LocalFile::new(path)),
+        UriSchemes::S3 => Box::new(S3File::new()),
+    };
+    Ok(uploadable)
+}
+
+// Writes to a temporary file next to `path` first and renames it into place, the rename
+// is atomic so a crash while writing never leaves a partial file behind
+pub(crate) fn write_atomically<F>(path: &Path, write: F) -> Result<(), PaymentError>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), PaymentError>,
+{
+    let mut tmp_path = OsString::from(path);
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let context = format!("Could not write {}", path.display());
+    let mut writer = BufWriter::new(
+        File::create(&tmp_path).map_err(PaymentError::storage_error(context.clone()))?,
+    );
+    write(&mut writer)?;
+    let file = writer
+        .into_inner()
+        .map_err(|e| PaymentError::storage_error(context.clone())(e.into_error()))?;
+    file.sync_all()
+        .map_err(PaymentError::storage_error(context.clone()))?;
+    fs::rename(&tmp_path, path).map_err(PaymentError::storage_error(context))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_upload_local_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("accounts.csv");
+        fs::write(&path, "old contents").unwrap();
+
+        let uri = format!("file://{}", path.to_str().unwrap());
+        uploadable_for_uri(&uri)
+            .unwrap()
+            .upload("client,available,held,total,locked\n".as_bytes())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "client,available,held,total,locked\n"
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(uploadable_for_uri("ftp://accounts.csv").is_err());
+    }
+}
@@ -1,8 +1,16 @@
 pub mod engine;
 
+// The async ingestion layer, the synchronous core is `AccountService` in `engine::payments`
+#[cfg(feature = "runtime")]
+pub use crate::engine::builder::{Engine, EngineBuilder};
+
+#[cfg(feature = "runtime")]
 use crate::engine::ingestion::{IngestionService, PaymentsQueue};
+#[cfg(feature = "runtime")]
 use crate::engine::payments::AccountService;
 
+// Fresh in-memory engine with 1 worker, see `EngineBuilder` for anything else
+#[cfg(feature = "runtime")]
 pub fn payments_engine() -> (IngestionService, AccountService) {
     let payments_queue = PaymentsQueue::new();
     let account_service = AccountService::new();
@@ -1,27 +1,21 @@
 mod cli;
 
-use crate::cli::CLI;
-use payments_engine::payments_engine;
-use std::env;
+use crate::cli::{exit_code, CLI};
+use std::error::Error;
+use std::{env, process};
 
 #[tokio::main]
 async fn main() {
-    let (ingestion_service, account_service) = payments_engine();
-    let cli = CLI::new(ingestion_service.clone());
+    let cli = CLI::new();
     let cli_result = cli.execute(env::args().collect()).await;
 
     if let Some(cli_error) = cli_result.err() {
-        panic!("{:?}", cli_error);
-    }
-
-    ingestion_service.run().await;
-    let results = ingestion_service.shutdown_gracefully().await;
-    for result in results {
-        if let Some(_error) = result.err() {
-            // TODO: If an error happened on one of the workers during
-            // payment processing we could log it here
+        eprintln!("error[{}]: {}", cli_error.code(), cli_error);
+        let mut source = cli_error.source();
+        while let Some(cause) = source {
+            eprintln!("  caused by: {}", cause);
+            source = cause.source();
         }
+        process::exit(exit_code(&cli_error));
     }
-
-    account_service.print_accounts();
 }
@@ -1,11 +1,23 @@
+#![cfg(feature = "runtime")]
+
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use payments_engine::engine::payments::AccountService;
+    use payments_engine::engine::snapshot::{load_snapshot, save_snapshot};
     use payments_engine::payments_engine;
+    use std::path::Path;
 
     async fn run_test_file(name: &str) -> AccountService {
+        run_test_file_from_state(name, None).await
+    }
+
+    async fn run_test_file_from_state(name: &str, state: Option<&Path>) -> AccountService {
         let csv_file = format!("file://tests/resources/{}.csv", name);
         let (ingestion_service, account_service) = payments_engine();
+        if let Some(state) = state {
+            load_snapshot(&account_service, state).unwrap();
+        }
         ingestion_service
             .submit_payments_csv(&csv_file)
             .await
@@ -23,54 +35,131 @@ mod tests {
         // 2,2,0,2,false
         // 1,1.5,0,1.5,false
 
-        assert_eq!(account_service.get_account(1).unwrap().total(), 1.5);
-        assert_eq!(account_service.get_account(1).unwrap().available(), 1.5);
-        assert_eq!(account_service.get_account(1).unwrap().held(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            1.5
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            1.5
+        );
+        assert_eq!(account_service.get_account(1).unwrap().unwrap().held(), 0.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().locked(),
+            false
+        );
 
-        assert_eq!(account_service.get_account(2).unwrap().total(), 2.0);
-        assert_eq!(account_service.get_account(2).unwrap().available(), 2.0);
-        assert_eq!(account_service.get_account(2).unwrap().held(), 0.0);
-        assert_eq!(account_service.get_account(2).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().total(),
+            2.0
+        );
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().available(),
+            2.0
+        );
+        assert_eq!(account_service.get_account(2).unwrap().unwrap().held(), 0.0);
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().locked(),
+            false
+        );
 
-        assert_eq!(account_service.get_account(3).unwrap().total(), 0.0);
-        assert_eq!(account_service.get_account(3).unwrap().available(), 0.0);
-        assert_eq!(account_service.get_account(3).unwrap().held(), 0.0);
-        assert_eq!(account_service.get_account(3).unwrap().locked(), true);
+        assert_eq!(
+            account_service.get_account(3).unwrap().unwrap().total(),
+            0.0
+        );
+        assert_eq!(
+            account_service.get_account(3).unwrap().unwrap().available(),
+            0.0
+        );
+        assert_eq!(account_service.get_account(3).unwrap().unwrap().held(), 0.0);
+        assert_eq!(
+            account_service.get_account(3).unwrap().unwrap().locked(),
+            true
+        );
 
-        assert_eq!(account_service.get_account(4).unwrap().total(), 20.0);
-        assert_eq!(account_service.get_account(4).unwrap().available(), 20.0);
-        assert_eq!(account_service.get_account(4).unwrap().held(), 0.0);
-        assert_eq!(account_service.get_account(4).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(4).unwrap().unwrap().total(),
+            20.0
+        );
+        assert_eq!(
+            account_service.get_account(4).unwrap().unwrap().available(),
+            20.0
+        );
+        assert_eq!(account_service.get_account(4).unwrap().unwrap().held(), 0.0);
+        assert_eq!(
+            account_service.get_account(4).unwrap().unwrap().locked(),
+            false
+        );
     }
 
     #[tokio::test]
     async fn test_cannot_withdraw_more_than_balance() {
         let account_service = run_test_file("withdraw_more_than_balance").await;
-        assert_eq!(account_service.get_account(1).unwrap().total(), 1.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            1.0
+        );
     }
 
     #[tokio::test]
     async fn test_cannot_withdraw_held_funds() {
         let account_service = run_test_file("withdraw_more_than_balance").await;
-        assert_eq!(account_service.get_account(1).unwrap().total(), 1.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            1.0
+        );
     }
 
     #[tokio::test]
     async fn test_precision() {
         let account_service = run_test_file("precision").await;
-        assert_eq!(account_service.get_account(1).unwrap().total(), 0.123);
-        assert_eq!(account_service.get_account(2).unwrap().total(), 0.1234);
-        assert_eq!(account_service.get_account(3).unwrap().total(), 0.1234);
-        assert_eq!(account_service.get_account(4).unwrap().total(), 0.1235);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            0.123
+        );
+        assert_eq!(
+            account_service.get_account(2).unwrap().unwrap().total(),
+            0.1234
+        );
+        assert_eq!(
+            account_service.get_account(3).unwrap().unwrap().total(),
+            0.1234
+        );
+        assert_eq!(
+            account_service.get_account(4).unwrap().unwrap().total(),
+            0.1235
+        );
     }
 
     #[tokio::test]
     async fn test_whitespace() {
         let account_service = run_test_file("whitespace").await;
-        assert_eq!(account_service.get_account(1).unwrap().total(), 8.0);
-        assert_eq!(account_service.get_account(1).unwrap().available(), 8.0);
-        assert_eq!(account_service.get_account(1).unwrap().held(), 0.0);
-        assert_eq!(account_service.get_account(1).unwrap().locked(), false);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().total(),
+            8.0
+        );
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().available(),
+            8.0
+        );
+        assert_eq!(account_service.get_account(1).unwrap().unwrap().held(), 0.0);
+        assert_eq!(
+            account_service.get_account(1).unwrap().unwrap().locked(),
+            false
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_on_top_of_previous_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = dir.path().join("day1.snapshot");
+        let day1 = run_test_file("day1").await;
+        save_snapshot(&day1, &state).unwrap();
+
+        let day2 = run_test_file_from_state("day2", Some(&state)).await;
+        assert_eq!(day2.get_account(1).unwrap().unwrap().available(), 1.0);
+        assert_eq!(day2.get_account(1).unwrap().unwrap().held(), 10.0);
+        assert_eq!(day2.get_account(1).unwrap().unwrap().total(), 11.0);
+        assert_eq!(day2.get_account(2).unwrap().unwrap().total(), 2.5);
     }
 }